@@ -0,0 +1,719 @@
+use crate::{
+    config::{AppConfig, SharedConfig},
+    control::{self, ControlRequest},
+    daemon,
+    database::{self, Database, History, HistoryFilter, PauseState, QueueItem},
+    export::{self, Format},
+    sftp::{self, SftpManager},
+    *,
+};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use clap::{Args, Parser, Subcommand};
+use serde_json::{Value, json};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio_util::sync::CancellationToken;
+
+
+/// Small - uploads screenshots and files over SFTP and keeps the links
+#[derive(Debug, Parser)]
+#[command(name = "small", version)]
+pub struct Cli {
+    /// Configuration file used instead of the default one
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Runs the daemon (the default)
+    Daemon,
+
+    /// Uploads the files, inline when no daemon is running. "-" uploads the stdin.
+    Upload {
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// Name of the destination config, the scheduled one by default
+        #[arg(long, value_name = "NAME")]
+        config: Option<String>,
+
+        /// File name of the stdin upload
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Extension of the stdin upload, "txt" by default
+        #[arg(long)]
+        ext: Option<String>,
+    },
+
+    /// Lists the latest uploads
+    History {
+        /// Amount of entries, the amount_history_load by default
+        #[arg(long)]
+        limit: Option<usize>,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        /// Prints JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Exports the history to JSON lines, CSV or a static HTML gallery
+    Export {
+        /// File written, the stdout by default
+        path: Option<PathBuf>,
+
+        /// jsonl, csv or html, guessed by the file extension by default
+        #[arg(long)]
+        format: Option<Format>,
+
+        /// Exports the queued files too (JSON lines only)
+        #[arg(long)]
+        queue: bool,
+
+        /// Amount of the latest entries, all by default
+        #[arg(long)]
+        limit: Option<usize>,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+
+    /// Imports the history (and queue) exported to JSON lines, merging the entries
+    /// already there by their uuid or content hash
+    Import { path: PathBuf },
+
+    /// Adds the tags to the history entry
+    Tag {
+        uuid: String,
+
+        #[arg(required_unless_present = "clear")]
+        tags: Vec<String>,
+
+        /// Removes the given tags instead
+        #[arg(long, conflicts_with = "clear")]
+        remove: bool,
+
+        /// Removes all tags
+        #[arg(long)]
+        clear: bool,
+    },
+
+    /// Sets the note of the history entry
+    Note {
+        uuid: String,
+
+        #[arg(required_unless_present = "clear")]
+        note: Option<String>,
+
+        /// Removes the note
+        #[arg(long, conflicts_with = "note")]
+        clear: bool,
+    },
+
+    /// Manages the upload queue
+    Queue {
+        #[command(subcommand)]
+        action: Option<QueueAction>,
+    },
+
+    /// Manages the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+
+    /// Dumps the database to the file, timestamped in the dumps directory by default
+    Dump { path: Option<PathBuf> },
+
+    /// Shows the state of the running daemon
+    Status,
+
+    /// Pauses uploading, files are still queued. Survives restarts.
+    Pause {
+        /// Resumes automatically after the duration "30m", "2h", at the time "18:00"
+        /// or the date and time "2025-12-24 18:00"
+        #[arg(long)]
+        until: Option<String>,
+    },
+
+    /// Resumes uploading
+    Resume,
+
+    /// Makes the running daemon use the named config regardless of schedules
+    Use {
+        #[arg(required_unless_present = "schedules")]
+        name: Option<String>,
+
+        /// Selects configs by their schedules again
+        #[arg(long, conflicts_with = "name")]
+        schedules: bool,
+    },
+
+    /// Reloads the configuration of the running daemon
+    Reload,
+
+    /// Replaces the database content with the dump, also of the running daemon.
+    /// The current database is backed up next to it first.
+    Restore {
+        /// Path, file name in the dumps directory or "latest"
+        #[arg(required_unless_present = "list")]
+        dump: Option<PathBuf>,
+
+        /// Lists the dumps with their dates and row counts
+        #[arg(long, conflicts_with = "dump")]
+        list: bool,
+    },
+}
+
+
+/// Conditions of the history entries listed or exported
+#[derive(Debug, Args)]
+pub struct FilterArgs {
+    /// Only entries with the words (or their beginnings) in the file name, link or config
+    #[arg(long)]
+    search: Option<String>,
+
+    /// Only entries uploaded since: a duration ago "7d", a date "2025-09-01"
+    /// or a date and time "2025-09-01 18:00"
+    #[arg(long)]
+    since: Option<String>,
+
+    /// Only entries uploaded until the end of the date or the date and time
+    #[arg(long)]
+    until: Option<String>,
+
+    /// Only entries of the MIME type "image/png", its type "image" or the extension "png"
+    #[arg(long = "type", value_name = "TYPE")]
+    kind: Option<String>,
+
+    /// Only entries with the tag
+    #[arg(long)]
+    tag: Option<String>,
+
+    /// Only entries listed after the one with the uuid, the next page of the older ones
+    #[arg(long, value_name = "UUID")]
+    after: Option<String>,
+}
+
+
+impl FilterArgs {
+    fn filter(&self, limit: usize) -> Result<HistoryFilter> {
+        Ok(HistoryFilter {
+            after: self.after.clone(),
+            ..HistoryFilter::new(
+                self.search.as_deref(),
+                self.since.as_deref(),
+                self.until.as_deref(),
+                self.kind.as_deref(),
+                self.tag.as_deref(),
+                limit,
+            )?
+        })
+    }
+}
+
+
+#[derive(Debug, Subcommand)]
+pub enum QueueAction {
+    /// Lists the queued files (the default)
+    List,
+
+    /// Queues the files of the history entries again
+    Retry {
+        #[arg(required = true)]
+        uuids: Vec<String>,
+    },
+
+    /// Removes the files from the queue
+    Drop {
+        #[arg(required_unless_present = "all")]
+        uuids: Vec<String>,
+
+        /// Removes all queued files
+        #[arg(long, conflicts_with = "uuids")]
+        all: bool,
+    },
+}
+
+
+#[derive(Debug, Copy, Clone, Subcommand)]
+pub enum ConfigAction {
+    /// Validates the configuration file and reports all problems
+    Check,
+}
+
+
+impl Cli {
+    pub fn is_daemon(&self) -> bool {
+        matches!(self.command, None | Some(Command::Daemon))
+    }
+
+
+    pub fn app_config(&self) -> Result<AppConfig> {
+        match &self.config {
+            Some(config_file) => AppConfig::load(config_file),
+            None => AppConfig::new(),
+        }
+    }
+
+
+    /// Runs the command, returns the exit code of the process
+    pub async fn run(self) -> Result<i32> {
+        let app_config = self.app_config()?;
+        match self.command.unwrap_or(Command::Daemon) {
+            Command::Daemon => return Ok(daemon::run(app_config).await?.exit_code()),
+            Command::Upload {
+                files,
+                config,
+                name,
+                ext,
+            } => {
+                let stdin = StdinUpload {
+                    name,
+                    ext,
+                };
+                upload(app_config, &files, config.as_deref(), stdin).await
+            }
+            Command::History {
+                limit,
+                filter,
+                json,
+            } => {
+                let filter = filter.filter(limit.unwrap_or(app_config.amount_history_load))?;
+                history(&app_config, &filter, json)
+            }
+            Command::Export {
+                path,
+                format,
+                queue,
+                limit,
+                filter,
+            } => {
+                let filter = filter.filter(limit.unwrap_or(usize::MAX))?;
+                export(&app_config, path.as_deref(), format, queue, &filter)
+            }
+            Command::Import {
+                path,
+            } => {
+                let file = fs::File::open(&path)
+                    .context(format!("Cannot open the import file: {path:?}"))?;
+                let records = export::read_records(io::BufReader::new(file))?;
+                let report = export::import(&open_database(&app_config)?, records)?;
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                Ok(())
+            }
+            Command::Tag {
+                uuid,
+                tags,
+                remove,
+                clear,
+            } => tag(&app_config, &uuid, &tags, remove, clear),
+            Command::Note {
+                uuid,
+                note,
+                ..
+            } => {
+                let database = open_database(&app_config)?;
+                if !database.set_note(&uuid, note.as_deref())? {
+                    anyhow::bail!("No history entry: {uuid}");
+                }
+                println!("{}", history_line(&find_entry(&database, &uuid)?));
+                Ok(())
+            }
+            Command::Queue {
+                action,
+            } => queue(app_config, action.unwrap_or(QueueAction::List)).await,
+            Command::Config {
+                action: ConfigAction::Check,
+            } => {
+                println!(
+                    "Configuration file: {:?} is valid ({} configs)",
+                    app_config.config_file,
+                    app_config.configs.len()
+                );
+                Ok(())
+            }
+            Command::Dump {
+                path,
+            } => dump(&app_config, path),
+            Command::Restore {
+                dump, ..
+            } => {
+                match dump {
+                    Some(dump) => restore(&app_config, &dump),
+                    None => list_dumps(&app_config),
+                }
+            }
+            Command::Status => print_control(&app_config, &ControlRequest::Status),
+            Command::Pause {
+                until,
+            } => {
+                set_pause(
+                    &app_config,
+                    &ControlRequest::Pause {
+                        until,
+                    },
+                )
+            }
+            Command::Resume => set_pause(&app_config, &ControlRequest::Resume),
+            Command::Use {
+                name, ..
+            } => {
+                print_control(
+                    &app_config,
+                    &ControlRequest::ForceConfig {
+                        name,
+                    },
+                )
+            }
+            Command::Reload => print_control(&app_config, &ControlRequest::Reload),
+        }?;
+        Ok(0)
+    }
+}
+
+
+/// Naming of the file spooled from the stdin
+#[derive(Debug)]
+struct StdinUpload {
+    name: Option<String>,
+    ext: Option<String>,
+}
+
+
+async fn upload(
+    app_config: AppConfig,
+    files: &[String],
+    config_name: Option<&str>,
+    stdin: StdinUpload,
+) -> Result<()> {
+    if files.iter().filter(|file| *file == "-").count() > 1 {
+        anyhow::bail!("The stdin (\"-\") can be uploaded only once");
+    }
+    let database = Arc::new(open_database(&app_config)?);
+    for file in files {
+        let file = if file == "-" {
+            let spooled = spool_stream(
+                &mut io::stdin().lock(),
+                &app_config.stdin_spool_dir(),
+                stdin.name.as_deref(),
+                stdin.ext.as_deref(),
+            )?;
+            spooled.to_string_lossy().to_string()
+        } else {
+            file.clone()
+        };
+        let link = sftp::enqueue_file(&app_config, &database, &file, config_name)?;
+        println!("{link}");
+    }
+    upload_queue(app_config, database).await
+}
+
+
+fn history(app_config: &AppConfig, filter: &HistoryFilter, json: bool) -> Result<()> {
+    let entries = open_database(app_config)?.search_history(filter)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        for entry in &entries {
+            println!("{}", history_line(entry));
+        }
+    }
+    Ok(())
+}
+
+
+fn history_line(entry: &History) -> String {
+    let timestamp = entry.local_time();
+    let mut line = format!(
+        "{timestamp}  {}  {}  {}",
+        entry.uuid, entry.content, entry.file
+    );
+    let details = entry.details();
+    if !details.is_empty() {
+        line.push_str(&format!("  ({details})"));
+    }
+    if !entry.tags.is_empty() {
+        let tags: Vec<String> = entry.tags.iter().map(|tag| format!("#{tag}")).collect();
+        line.push_str(&format!("  {}", tags.join(" ")));
+    }
+    if let Some(note) = &entry.note {
+        line.push_str(&format!("  \"{note}\""));
+    }
+    line
+}
+
+
+/// Writes the export to the file or the stdout
+fn export(
+    app_config: &AppConfig,
+    path: Option<&Path>,
+    format: Option<Format>,
+    queue: bool,
+    filter: &HistoryFilter,
+) -> Result<()> {
+    let format = format
+        .or_else(|| path.and_then(Format::from_path))
+        .unwrap_or_default();
+    let database = open_database(app_config)?;
+    match path {
+        Some(path) => {
+            let file = fs::File::create(path)
+                .context(format!("Cannot create the export file: {path:?}"))?;
+            let mut writer = io::BufWriter::new(file);
+            let exported = export::export(&database, filter, format, queue, &mut writer)?;
+            println!("Exported {exported} history entries to: {}", path.display());
+        }
+        None => {
+            export::export(&database, filter, format, queue, &mut io::stdout().lock())?;
+        }
+    }
+    Ok(())
+}
+
+
+/// Adds, removes or clears the tags of the history entry
+fn tag(
+    app_config: &AppConfig,
+    uuid: &str,
+    tags: &[String],
+    remove: bool,
+    clear: bool,
+) -> Result<()> {
+    let database = open_database(app_config)?;
+    let entry = find_entry(&database, uuid)?;
+    let given = database::normalize_tags(tags);
+    let tags: Vec<String> = if clear {
+        Vec::new()
+    } else if remove {
+        entry
+            .tags
+            .into_iter()
+            .filter(|tag| !given.contains(tag))
+            .collect()
+    } else {
+        entry.tags.into_iter().chain(given).collect()
+    };
+    database.set_tags(uuid, &tags)?;
+    println!("{}", history_line(&find_entry(&database, uuid)?));
+    Ok(())
+}
+
+
+fn find_entry(database: &Database, uuid: &str) -> Result<History> {
+    database
+        .find_history(uuid)?
+        .context(format!("No history entry: {uuid}"))
+}
+
+
+fn dump(app_config: &AppConfig, path: Option<PathBuf>) -> Result<()> {
+    if daemon::is_running(app_config) {
+        let result = control(
+            app_config,
+            &ControlRequest::Dump {
+                path,
+            },
+        )?;
+        println!("{}", result["path"].as_str().unwrap_or_default());
+        return Ok(());
+    }
+    let database = open_database(app_config)?;
+    let path = match path {
+        Some(path) => {
+            database.dump_to_file(&path)?;
+            path
+        }
+        None => {
+            dumps::create(
+                &database,
+                &app_config.db_dumps_dir(),
+                &app_config.dumps,
+                false,
+            )?
+            .context("Database dump skipped")?
+        }
+    };
+    println!("{}", path.display());
+    Ok(())
+}
+
+
+/// Restores the dump through the running daemon, or directly in the database
+fn restore(app_config: &AppConfig, dump: &Path) -> Result<()> {
+    let dump = std::path::absolute(dumps::resolve(&app_config.db_dumps_dir(), dump)?)?;
+    let backup = if daemon::is_running(app_config) {
+        let result = control(
+            app_config,
+            &ControlRequest::Restore {
+                dump: dump.clone(),
+            },
+        )?;
+        PathBuf::from(result["backup"].as_str().unwrap_or_default())
+    } else {
+        open_database(app_config)?.restore_from_file(&dump)?
+    };
+    println!("Database restored from: {}", dump.display());
+    println!("The previous database backed up to: {}", backup.display());
+    Ok(())
+}
+
+
+fn list_dumps(app_config: &AppConfig) -> Result<()> {
+    let dumps_dir = app_config.db_dumps_dir();
+    for dump in dumps::list(&dumps_dir)? {
+        match dumps::inspect(&dump) {
+            Ok(info) => {
+                println!(
+                    "{}  {:>10}  history: {:>6}  queue: {:>4}  {}",
+                    info.created,
+                    human_size(info.size),
+                    info.history,
+                    info.queue,
+                    info.path.display()
+                )
+            }
+            Err(e) => println!("{}  unreadable: {e:#}", dump.path.display()),
+        }
+    }
+    Ok(())
+}
+
+
+async fn queue(app_config: AppConfig, action: QueueAction) -> Result<()> {
+    let database = Arc::new(open_database(&app_config)?);
+    match action {
+        QueueAction::List => {
+            let queue: Vec<QueueItem> = if daemon::is_running(&app_config) {
+                serde_json::from_value(control(&app_config, &ControlRequest::Queue)?)?
+            } else {
+                database.get_queue()?
+            };
+            for item in queue {
+                println!(
+                    "{}  {}  {}",
+                    item.uuid,
+                    item.local_file,
+                    item.config.as_deref().unwrap_or("-")
+                );
+            }
+            Ok(())
+        }
+        QueueAction::Retry {
+            uuids,
+        } => {
+            for uuid in &uuids {
+                let entry = database
+                    .find_history(uuid)?
+                    .context(format!("No history entry: {uuid}"))?;
+                let config = app_config.config_for(entry.config.as_deref())?;
+                let item = QueueItem::new(&entry.file, &config);
+                database.add_to_queue(&item)?;
+                println!("{}", config.link(&item.uuid, &item.local_file));
+            }
+            upload_queue(app_config, database).await
+        }
+        QueueAction::Drop {
+            uuids,
+            all,
+        } => {
+            let removed = if all {
+                database.clear_queue()?
+            } else {
+                let mut removed = 0;
+                for uuid in &uuids {
+                    removed += database.remove_from_queue(uuid)?;
+                }
+                removed
+            };
+            println!("Removed {removed} queued file(s)");
+            Ok(())
+        }
+    }
+}
+
+
+/// Uploads the queue inline, unless the running daemon takes care of it or it's paused
+async fn upload_queue(app_config: AppConfig, database: Arc<Database>) -> Result<()> {
+    if daemon::is_running(&app_config) {
+        info!("Daemon is running, it will upload the queued files");
+        return Ok(());
+    }
+    if database.get_pause()?.is_active(Local::now().timestamp()) {
+        warn!("Uploading is paused, the files stay queued until: small resume");
+        return Ok(());
+    }
+    // NOTE: the lock keeps a daemon started meanwhile, or another command, off the same queue
+    let _instance_lock = match daemon::InstanceLock::acquire(&app_config.lock_file_path()) {
+        Ok(instance_lock) => instance_lock,
+        Err(e) => {
+            warn!("{e}, the files stay queued");
+            return Ok(());
+        }
+    };
+    let (sftp_manager, _rx) = SftpManager::new(SharedConfig::new(app_config), database);
+    sftp_manager.process_queue(&CancellationToken::new()).await
+}
+
+
+/// Sends the request to the running daemon
+fn control(app_config: &AppConfig, request: &ControlRequest) -> Result<Value> {
+    if !daemon::is_running(app_config) {
+        anyhow::bail!(
+            "No daemon is running (no control socket: {:?})",
+            app_config.control_socket_path()
+        );
+    }
+    control::send(&app_config.control_socket_path(), request)
+}
+
+
+fn print_control(app_config: &AppConfig, request: &ControlRequest) -> Result<()> {
+    let result = control(app_config, request)?;
+    println!("{}", serde_json::to_string_pretty(&result)?);
+    Ok(())
+}
+
+
+/// Pauses or resumes uploading through the running daemon, or directly in the database
+fn set_pause(app_config: &AppConfig, request: &ControlRequest) -> Result<()> {
+    if daemon::is_running(app_config) {
+        return print_control(app_config, request);
+    }
+    let pause = match request {
+        ControlRequest::Pause {
+            until,
+        } => PauseState::until(until.as_deref())?,
+        _ => PauseState::default(),
+    };
+    open_database(app_config)?.set_pause(&pause)?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&json!({
+            "paused": pause.paused,
+            "paused_until": pause.until_local(),
+        }))?
+    );
+    Ok(())
+}
+
+
+fn open_database(app_config: &AppConfig) -> Result<Database> {
+    let project_dir = app_config.project_dir();
+    fs::create_dir_all(&project_dir)
+        .context(format!("Cannot create project directory: {project_dir:?}"))?;
+    Database::new(app_config.database_path())
+}
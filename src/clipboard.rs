@@ -0,0 +1,123 @@
+use crate::{
+    config::{AppConfig, SharedConfig},
+    database::{Database, QueueItem},
+    *,
+};
+use anyhow::Result;
+use clippers::{Clipboard, ClipperData};
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+
+#[derive(Debug)]
+pub struct ClipboardWatcher {
+    config: SharedConfig,
+    database: Arc<Database>,
+}
+
+
+impl ClipboardWatcher {
+    pub fn new(config: SharedConfig, database: Arc<Database>) -> Self {
+        ClipboardWatcher {
+            config,
+            database,
+        }
+    }
+
+
+    pub async fn start(self: Arc<Self>, shutdown: CancellationToken) -> Result<()> {
+        info!("Launching Small Clipboard Handler");
+
+        // NOTE: an image already on the clipboard at start isn't a new capture
+        let mut last_image = None;
+        let mut capturing = false;
+        let mut interval = time::interval(Duration::from_millis(
+            self.config.current().fs_check_interval,
+        ));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => (),
+                _ = shutdown.cancelled() => return Ok(()),
+            }
+            let app_config = self.config.current();
+            if !app_config.clipboard.capture_images {
+                capturing = false;
+                continue;
+            }
+            if !capturing {
+                capturing = true;
+                last_image = Self::read_image().map(|(hash, ..)| hash);
+                info!(
+                    "Capturing clipboard images to: {:?}",
+                    app_config.clipboard_spool_dir()
+                );
+            }
+
+            let Some((hash, width, height, rgba)) = Self::read_image() else {
+                last_image = None;
+                continue;
+            };
+            if last_image == Some(hash) {
+                continue;
+            }
+            last_image = Some(hash);
+
+            // NOTE: the PNG encoding and the database call block
+            let clipboard = self.clone();
+            let captured = tokio::task::spawn_blocking(move || {
+                clipboard.process_image(&app_config, width, height, &rgba)
+            })
+            .await;
+            if let Err(e) = captured.unwrap_or_else(|e| Err(e.into())) {
+                error!("Error capturing clipboard image: {e:?}");
+            }
+        }
+    }
+
+
+    /// Reads the image from the clipboard: (hash of pixels, width, height, RGBA pixels)
+    fn read_image() -> Option<(u64, u32, u32, Vec<u8>)> {
+        match Clipboard::get().read() {
+            Some(ClipperData::Image(image)) => {
+                let (width, height) = image.dimensions();
+                let rgba = image.into_raw().to_vec();
+                let mut hasher = DefaultHasher::new();
+                (width, height, &rgba).hash(&mut hasher);
+                Some((hasher.finish(), width, height, rgba))
+            }
+            _ => None,
+        }
+    }
+
+
+    /// Writes the image to the spool directory and queues it
+    pub fn process_image(
+        &self,
+        app_config: &AppConfig,
+        width: u32,
+        height: u32,
+        rgba: &[u8],
+    ) -> Result<()> {
+        let spool_dir = app_config.clipboard_spool_dir();
+        fs::create_dir_all(&spool_dir)?;
+        let timestamp = chrono::Local::now().format("%Y-%m-%d-%H-%M-%S-%3f");
+        let file_path = spool_dir.join(format!("clipboard-{timestamp}.png"));
+        image::save_buffer(&file_path, rgba, width, height, image::ColorType::Rgba8)?;
+        info!("Clipboard image ({width}x{height}) written to: {file_path:?}");
+
+        let config = app_config
+            .select_config()
+            .expect("One of configs should always be selected!");
+        let queue_item = QueueItem::new(&file_path.to_string_lossy(), &config);
+        self.database.add_to_queue(&queue_item)?;
+        debug!("Added clipboard image to queue: {file_path:?}");
+        Ok(())
+    }
+}
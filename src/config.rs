@@ -1,8 +1,21 @@
-use crate::*;
+use crate::{
+    schedule::Schedule,
+    secret::Secret,
+    validation::{self, ValidationReport},
+    *,
+};
 use anyhow::{Context, Result};
-use chrono::{Datelike, Local, NaiveTime, Weekday};
+use chrono::{Local, Weekday};
 use serde::{Deserialize, Serialize};
-use std::{fs, path::PathBuf};
+use std::{
+    fmt::Display,
+    fs,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::sync::watch;
 
 
 /// Multiple configurations for sync
@@ -12,22 +25,122 @@ pub struct Configs {
     pub notifications: NotificationSettings,
     pub sounds: SoundSettings,
     pub open_history_on_start: bool,
+    #[serde(default)]
+    pub clipboard: ClipboardSettings,
+    #[serde(default)]
+    pub dumps: DumpSettings,
+    #[serde(default)]
+    pub runtime: RuntimeSettings,
+    #[serde(default)]
+    pub auto_tags: Vec<AutoTags>,
 }
 
 /// A single configuration entry
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
 pub struct Config {
+    #[serde(default)]
+    pub name: String, // unique name, used to target the config explicitly
     pub username: String,
     pub hostname: String,
     pub ssh_key: String,
     pub ssh_port: u16,
     pub address: String,
     pub remote_path: String,
-    pub ssh_key_pass: String,
+    pub ssh_key_pass: Secret, // literal or: "env:VAR", "file:/path", "cmd:command"
     pub watch_path: String,
-    pub active_at: String, // hour range when to activate it: example: "9:01:00-15:55:00"
+    pub active_at: String, // hour ranges when to activate it: example: "9:01:00-15:55:00", "22:00-6:00, 12:00-13:00"
     pub active_on: Vec<Weekday>, // days of week when to activate it: example: ["Mon", "Tue", "Wed", "Thu", "Fri"]
+    #[serde(default)]
+    pub active_dates: Vec<String>, // dates when to activate it: example: ["2025-12-01..2025-12-31", "2026-01-02"]
+    #[serde(default)]
+    pub holidays: Vec<String>, // dates when it's never active: example: ["2025-12-24..2025-12-26"]
     pub default: bool,
+    #[serde(default)]
+    pub watcher: WatcherMode, // "native" (default) or "poll" for network and FUSE mounts
+    #[serde(default)]
+    pub on_delete: DeletePolicy, // what to do when a file is deleted from the watch_path
+    #[serde(default)]
+    pub bind_watch_path: bool, // always watch the watch_path and upload its files with this config
+    #[serde(default)]
+    pub tags: Vec<String>, // added to the history of every upload with this config, example: ["work"]
+    #[serde(skip)]
+    pub schedule: Schedule, // parsed from the active_* fields and holidays on load
+}
+
+
+impl Config {
+    /// The ssh_key is relative to the home directory, defaults to: .ssh/id_ed25519
+    pub fn ssh_key_path(&self) -> PathBuf {
+        let ssh_private_key = if self.ssh_key.is_empty() {
+            ".ssh/id_ed25519"
+        } else {
+            &self.ssh_key
+        };
+        home::home_dir()
+            .expect("Home dir has to be set!")
+            .join(ssh_private_key)
+    }
+
+
+    /// Public link of the uploaded file
+    pub fn link(&self, uuid: &str, local_file: &str) -> String {
+        format!("{}{uuid}{}", self.address, file_extension(local_file))
+    }
+
+
+    /// Parses the active_at, active_on, active_dates and holidays into the schedule
+    pub fn with_schedule(mut self) -> Result<Self> {
+        self.parse_schedule()?;
+        Ok(self)
+    }
+
+
+    /// Replaces the schedule with the one parsed from the active_at, active_on,
+    /// active_dates and holidays, keeps it when they're invalid
+    pub fn parse_schedule(&mut self) -> Result<()> {
+        self.schedule = Schedule::parse(
+            &self.active_at,
+            &self.active_on,
+            &self.active_dates,
+            &self.holidays,
+        )?;
+        Ok(())
+    }
+}
+
+
+/// Tags added to the history of the uploads from the path, example:
+/// [[auto_tags]] path = "/Users/me/Exports", tags = ["design"]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct AutoTags {
+    pub path: PathBuf, // directory, its subdirectories included
+    pub tags: Vec<String>,
+}
+
+
+/// Filesystem events backend used to watch the watch_path
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatcherMode {
+    /// FSEvents/inotify/kqueue - doesn't receive events from NFS, SMB and FUSE mounts
+    #[default]
+    Native,
+    /// Periodic scanning of the watch_path every fs_check_interval
+    Poll,
+}
+
+
+/// Action taken when a file disappears from the watch_path
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletePolicy {
+    /// Do nothing, pending uploads of missing files are dropped by the queue processor
+    #[default]
+    Ignore,
+    /// Drop pending uploads of the deleted file immediately
+    Drop,
+    /// Drop pending uploads and delete the already uploaded remote file
+    Remote,
 }
 
 
@@ -53,11 +166,164 @@ pub struct SoundSettings {
 }
 
 
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ClipboardSettings {
+    pub capture_images: bool, // upload images copied to the clipboard
+    #[serde(default)]
+    pub spool_path: String, // where clipboard images are written, defaults to: <project_dir>/clipboard
+}
+
+
+/// Optional [dumps] table: which of the periodic database dumps are kept.
+/// The latest ones are kept, then the newest dump of each of the recent days, weeks and months.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct DumpSettings {
+    pub keep_latest: usize,   // at least one is always kept
+    pub keep_daily: usize,    // days with a dump
+    pub keep_weekly: usize,   // weeks with a dump
+    pub keep_monthly: usize,  // months with a dump
+    pub compress: bool,       // gzip the dumps
+    pub skip_unchanged: bool, // no periodic dump when the database didn't change since the latest one
+}
+
+
+impl Default for DumpSettings {
+    fn default() -> Self {
+        DumpSettings {
+            keep_latest: 4,
+            keep_daily: 7,
+            keep_weekly: 4,
+            keep_monthly: 12,
+            compress: false,
+            skip_unchanged: true,
+        }
+    }
+}
+
+
+/// Optional [runtime] table. Every value can be overridden by the SMALL_<NAME> env variable,
+/// e.g. SMALL_FS_CHECK_INTERVAL=500. Unset values fall back to the defaults below.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct RuntimeSettings {
+    pub fs_check_interval: Option<u64>, // ms, default: 1000, range: 100..=60000
+    pub amount_history_load: Option<usize>, // default: 50, range: 1..=10000
+    pub db_autodump_interval: Option<u64>, // ms, default: 21600000 (6h), range: 60000..=604800000
+    pub ssh_connection_timeout: Option<u64>, // ms, default: 30000, range: 1000..=300000
+    pub sftp_buffer_size: Option<usize>,   // bytes, default: 262144, range: 4096..=16777216
+    pub webapi_port: Option<u16>, // default: 8000 (prod), 8001 (dev), 8002 (test), range: 1024..=65535
+    pub shutdown_timeout: Option<u64>, // ms, default: 30000, range: 1000..=600000
+    pub max_restarts: Option<u32>, // failures in a row before giving up a subsystem, default: 5, range: 0..=1000
+}
+
+
+pub const DEFAULT_FS_CHECK_INTERVAL: u64 = 1000;
+pub const DEFAULT_AMOUNT_HISTORY_LOAD: usize = 50;
+pub const DEFAULT_DB_AUTODUMP_INTERVAL: u64 = 21600000;
+pub const DEFAULT_SSH_CONNECTION_TIMEOUT: u64 = 30000;
+pub const DEFAULT_SFTP_BUFFER_SIZE: usize = 262144;
+pub const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30000;
+pub const DEFAULT_MAX_RESTARTS: u32 = 5;
+
+
+impl RuntimeSettings {
+    /// Applies the SMALL_* overrides returned by the lookup (the process environment)
+    pub fn with_overrides<F>(self, lookup: F) -> Result<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        Ok(RuntimeSettings {
+            fs_check_interval: Self::overridden(
+                &lookup,
+                "fs_check_interval",
+                self.fs_check_interval,
+            )?,
+            amount_history_load: Self::overridden(
+                &lookup,
+                "amount_history_load",
+                self.amount_history_load,
+            )?,
+            db_autodump_interval: Self::overridden(
+                &lookup,
+                "db_autodump_interval",
+                self.db_autodump_interval,
+            )?,
+            ssh_connection_timeout: Self::overridden(
+                &lookup,
+                "ssh_connection_timeout",
+                self.ssh_connection_timeout,
+            )?,
+            sftp_buffer_size: Self::overridden(
+                &lookup,
+                "sftp_buffer_size",
+                self.sftp_buffer_size,
+            )?,
+            webapi_port: Self::overridden(&lookup, "webapi_port", self.webapi_port)?,
+            shutdown_timeout: Self::overridden(
+                &lookup,
+                "shutdown_timeout",
+                self.shutdown_timeout,
+            )?,
+            max_restarts: Self::overridden(&lookup, "max_restarts", self.max_restarts)?,
+        })
+    }
+
+
+    fn overridden<F, T>(lookup: &F, name: &str, configured: Option<T>) -> Result<Option<T>>
+    where
+        F: Fn(&str) -> Option<String>,
+        T: FromStr,
+    {
+        let variable = format!("SMALL_{}", name.to_uppercase());
+        match lookup(&variable) {
+            Some(value) => {
+                value
+                    .trim()
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| anyhow::anyhow!("Invalid value of {variable}: {value:?}"))
+            }
+            None => Ok(configured),
+        }
+    }
+
+
+    /// Configured value or the default. Values out of the allowed range are reported.
+    fn value<T>(
+        report: &mut ValidationReport,
+        name: &str,
+        configured: Option<T>,
+        default: T,
+        range: RangeInclusive<T>,
+    ) -> T
+    where
+        T: PartialOrd + Display + Copy,
+    {
+        let value = configured.unwrap_or(default);
+        if !range.contains(&value) {
+            report.push(
+                format!("runtime.{name}"),
+                format!(
+                    "{value} is out of the allowed range: {}..={}",
+                    range.start(),
+                    range.end()
+                ),
+            );
+        }
+        value
+    }
+}
+
+
 #[derive(Debug, Clone, Default)]
 pub struct AppConfig {
+    pub config_file: PathBuf,
     pub configs: Vec<Config>,
     pub notifications: NotificationSettings,
     pub sounds: SoundSettings,
+    pub clipboard: ClipboardSettings,
+    pub dumps: DumpSettings,
     pub open_history_on_start: bool,
     pub env: String,
     pub fs_check_interval: u64,
@@ -66,67 +332,199 @@ pub struct AppConfig {
     pub ssh_connection_timeout: u64,
     pub sftp_buffer_size: usize,
     pub webapi_port: u16,
+    pub shutdown_timeout: u64,
+    pub max_restarts: u32,
+    pub auto_tags: Vec<AutoTags>,
+    pub forced_config: Option<String>, // name of the config used regardless of schedules
+}
+
+
+/// The current AppConfig shared by all subsystems.
+/// Replaced atomically when the config.toml is reloaded.
+#[derive(Debug, Clone)]
+pub struct SharedConfig {
+    tx: Arc<watch::Sender<Arc<AppConfig>>>,
+}
+
+
+impl SharedConfig {
+    pub fn new(config: AppConfig) -> Self {
+        let (tx, _rx) = watch::channel(Arc::new(config));
+        SharedConfig {
+            tx: Arc::new(tx),
+        }
+    }
+
+
+    /// Snapshot of the current configuration
+    pub fn current(&self) -> Arc<AppConfig> {
+        self.tx.borrow().clone()
+    }
+
+
+    pub fn replace(&self, config: AppConfig) {
+        self.tx.send_replace(Arc::new(config));
+    }
+
+
+    /// Receiver notified on every configuration change
+    pub fn subscribe(&self) -> watch::Receiver<Arc<AppConfig>> {
+        self.tx.subscribe()
+    }
+
+
+    /// Uses the named config regardless of schedules, None brings the schedules back
+    pub fn force_config(&self, name: Option<&str>) -> Result<()> {
+        let current = self.current();
+        if let Some(name) = name {
+            current.find_config(name)?;
+        }
+        self.replace(AppConfig {
+            forced_config: name.map(String::from),
+            ..(*current).clone()
+        });
+        Ok(())
+    }
 }
 
 
 impl AppConfig {
     pub fn new() -> Result<Self> {
+        Self::load(&Self::default_config_file())
+    }
+
+
+    pub fn load(config_file: &Path) -> Result<Self> {
         let env = std::env::var("ENV").unwrap_or_else(|_| "prod".to_string());
-        let config_file = Self::default_config_file();
 
         if !config_file.exists() {
             anyhow::bail!("No configuration file: {:?}", config_file);
         }
 
-        let config_content = fs::read_to_string(&config_file)
+        let config_content = fs::read_to_string(config_file)
             .context(format!("Cannot open config file: {:?}", config_file))?;
 
         let config: Configs =
             toml::from_str(&config_content).context("Failed to parse config file")?;
 
-        for config in &config.configs {
-            Self::validate_config(config)?;
+        let mut report = ValidationReport::default();
+        let mut configs = config.configs;
+        validation::validate_configs(&mut configs, &mut report);
+        for (index, auto_tags) in config.auto_tags.iter().enumerate() {
+            if !auto_tags.path.is_absolute() {
+                report.push(
+                    format!("auto_tags[{index}].path"),
+                    format!("{:?} has to be an absolute path", auto_tags.path),
+                );
+            }
         }
 
-        let webapi_port = match env.as_str() {
+        let runtime = report
+            .check(
+                "runtime",
+                config
+                    .runtime
+                    .with_overrides(|variable| std::env::var(variable).ok()),
+            )
+            .unwrap_or_default();
+        let default_webapi_port = match env.as_str() {
             "dev" => 8001,
             "test" => 8002,
             _ => 8000,
         };
+        let fs_check_interval = RuntimeSettings::value(
+            &mut report,
+            "fs_check_interval",
+            runtime.fs_check_interval,
+            DEFAULT_FS_CHECK_INTERVAL,
+            100..=60000,
+        );
+        let amount_history_load = RuntimeSettings::value(
+            &mut report,
+            "amount_history_load",
+            runtime.amount_history_load,
+            DEFAULT_AMOUNT_HISTORY_LOAD,
+            1..=10000,
+        );
+        let db_autodump_interval = RuntimeSettings::value(
+            &mut report,
+            "db_autodump_interval",
+            runtime.db_autodump_interval,
+            DEFAULT_DB_AUTODUMP_INTERVAL,
+            60000..=604800000,
+        );
+        let ssh_connection_timeout = RuntimeSettings::value(
+            &mut report,
+            "ssh_connection_timeout",
+            runtime.ssh_connection_timeout,
+            DEFAULT_SSH_CONNECTION_TIMEOUT,
+            1000..=300000,
+        );
+        let sftp_buffer_size = RuntimeSettings::value(
+            &mut report,
+            "sftp_buffer_size",
+            runtime.sftp_buffer_size,
+            DEFAULT_SFTP_BUFFER_SIZE,
+            4096..=16777216,
+        );
+        let webapi_port = RuntimeSettings::value(
+            &mut report,
+            "webapi_port",
+            runtime.webapi_port,
+            default_webapi_port,
+            1024..=65535,
+        );
+        let shutdown_timeout = RuntimeSettings::value(
+            &mut report,
+            "shutdown_timeout",
+            runtime.shutdown_timeout,
+            DEFAULT_SHUTDOWN_TIMEOUT,
+            1000..=600000,
+        );
+        let max_restarts = RuntimeSettings::value(
+            &mut report,
+            "max_restarts",
+            runtime.max_restarts,
+            DEFAULT_MAX_RESTARTS,
+            0..=1000,
+        );
+        report.into_result(config_file)?;
 
         Ok(AppConfig {
-            configs: config.configs.clone(),
+            config_file: config_file.to_path_buf(),
+            configs,
             notifications: config.notifications,
             sounds: config.sounds,
+            clipboard: config.clipboard,
+            dumps: config.dumps,
             env,
             open_history_on_start: config.open_history_on_start,
-            fs_check_interval: 1000, // ms
-            amount_history_load: 50,
-            db_autodump_interval: 21600000, // 6 hours in ms
-            ssh_connection_timeout: 30000,
-            sftp_buffer_size: 262144,
+            fs_check_interval,
+            amount_history_load,
+            db_autodump_interval,
+            ssh_connection_timeout,
+            sftp_buffer_size,
             webapi_port,
+            shutdown_timeout,
+            max_restarts,
+            auto_tags: config.auto_tags,
+            forced_config: None,
         })
     }
 
 
-    fn validate_config(config: &Config) -> Result<()> {
-        if config.username.is_empty() {
-            anyhow::bail!("Required configuration value: username is empty!");
-        }
-        if config.hostname.is_empty() {
-            anyhow::bail!("Required configuration value: hostname is empty!");
-        }
-        if config.ssh_port == 0 {
-            anyhow::bail!("Required configuration value: ssh_port is zero!");
-        }
-        if config.address.is_empty() {
-            anyhow::bail!("Required configuration value: address is empty!");
-        }
-        if config.remote_path.is_empty() {
-            anyhow::bail!("Required configuration value: remote_path is empty!");
-        }
-        Ok(())
+    pub fn log_runtime_settings(&self) {
+        info!(
+            "Runtime settings: fs_check_interval: {}ms, amount_history_load: {}, db_autodump_interval: {}ms, ssh_connection_timeout: {}ms, sftp_buffer_size: {}B, webapi_port: {}, shutdown_timeout: {}ms, max_restarts: {}",
+            self.fs_check_interval,
+            self.amount_history_load,
+            self.db_autodump_interval,
+            self.ssh_connection_timeout,
+            self.sftp_buffer_size,
+            self.webapi_port,
+            self.shutdown_timeout,
+            self.max_restarts
+        );
     }
 
 
@@ -139,34 +537,16 @@ impl AppConfig {
     }
 
 
-    // selects the current config based on the time
+    // selects the current config based on the schedule
     pub fn select_config(&self) -> Result<Config> {
-        let now = Local::now();
-        let time = now.time();
-        let weekday = now.weekday();
+        if let Some(name) = &self.forced_config {
+            debug!("Selected forced config: {name}");
+            return self.find_config(name);
+        }
+        let now = Local::now().naive_local();
 
         let configs = &self.configs;
-        let config = configs.iter().find(|cfg| {
-            if !cfg.active_on.is_empty() && !cfg.active_on.contains(&weekday) {
-                debug!("Active_on doesn't contain the current day: {weekday}");
-                return false;
-            }
-            if cfg.active_at.is_empty() {
-                debug!("Active_at is empty and thee current day is {weekday}, meaning the config is valid for the whole day");
-                return true;
-            }
-            let range: Vec<&str> = cfg.active_at.split('-').collect();
-            if range.len() != 2 {
-                error!("Wrong format of the time range. Should be: HH:MM:SS-HH:MM:SS");
-                return false;
-            }
-            let time_start = NaiveTime::parse_from_str(range[0], "%H:%M:%S")
-                .expect("Valid time is expected");
-            let time_end = NaiveTime::parse_from_str(range[1], "%H:%M:%S")
-                .expect("Valid time is expected");
-
-            time >= time_start && time <= time_end
-        });
+        let config = configs.iter().find(|cfg| cfg.schedule.is_active(now));
 
         let default_config = configs
             .iter()
@@ -174,27 +554,76 @@ impl AppConfig {
             .expect("One of configs has to be the default!");
 
         if let Some(cfg) = config {
-            debug!(
-                "Selected config: {configuration:?}",
-                configuration = Config {
-                    ssh_key_pass: String::from("<redacted>"), /* don't print the ssh key in logs */
-                    ..cfg.clone()
-                }
-            );
+            // NOTE: the ssh_key_pass Secret is redacted by its Debug
+            debug!("Selected config: {cfg:?}");
         }
 
         match config {
             Some(cfg) => Ok(cfg.clone()),
             None => {
-                debug!(
-                    "No config to select by the active_at range. Selecting the default one."
-                );
+                debug!("No config to select by the schedule. Selecting the default one.");
                 Ok(default_config.clone())
             }
         }
     }
 
 
+    pub fn find_config(&self, name: &str) -> Result<Config> {
+        self.configs
+            .iter()
+            .find(|cfg| cfg.name == name)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No config named: {name:?}"))
+    }
+
+
+    /// The named config, or the one selected by the schedule
+    pub fn config_for(&self, name: Option<&str>) -> Result<Config> {
+        match name {
+            Some(name) => self.find_config(name),
+            None => self.select_config(),
+        }
+    }
+
+
+    /// The config with the bound watch_path of the file, or the one selected by the schedule
+    pub fn config_for_path(&self, file_path: &str) -> Result<Config> {
+        let parent = Path::new(file_path).parent();
+        match self
+            .configs
+            .iter()
+            .find(|cfg| cfg.bind_watch_path && parent == Some(Path::new(&cfg.watch_path)))
+        {
+            Some(cfg) => Ok(cfg.clone()),
+            None => self.select_config(),
+        }
+    }
+
+
+    /// True when the file is inside the watch_path of any config
+    pub fn is_watched(&self, file_path: &Path) -> bool {
+        let Ok(file_path) = file_path.canonicalize() else {
+            return false;
+        };
+        self.configs.iter().any(|cfg| {
+            Path::new(&cfg.watch_path)
+                .canonicalize()
+                .is_ok_and(|watch_path| file_path.starts_with(watch_path))
+        })
+    }
+
+
+    /// Tags of the config and of the auto_tags paths containing the file
+    pub fn tags_for(&self, config: &Config, file_path: &str) -> Vec<String> {
+        let path_tags = self
+            .auto_tags
+            .iter()
+            .filter(|auto_tags| Path::new(file_path).starts_with(&auto_tags.path))
+            .flat_map(|auto_tags| auto_tags.tags.iter());
+        database::normalize_tags(&config.tags.iter().chain(path_tags).collect::<Vec<_>>())
+    }
+
+
     pub fn project_root_dir() -> PathBuf {
         let home = home::home_dir().expect("Could not determine home directory");
         home.join(Self::data_dir_base().trim_start_matches('/'))
@@ -211,6 +640,45 @@ impl AppConfig {
     }
 
 
+    pub fn clipboard_spool_dir(&self) -> PathBuf {
+        if self.clipboard.spool_path.is_empty() {
+            self.project_dir().join("clipboard")
+        } else {
+            PathBuf::from(&self.clipboard.spool_path)
+        }
+    }
+
+
+    /// Where the streams uploaded from the stdin are written
+    pub fn stdin_spool_dir(&self) -> PathBuf {
+        self.project_dir().join("spool")
+    }
+
+
+    /// Spool directory (of the clipboard or the stdin) of the file, None for the other files.
+    /// Files of the watch paths are the user's, even when the spool_path is there.
+    pub fn spool_dir_of(&self, file_path: &Path) -> Option<PathBuf> {
+        if self.is_watched(file_path) {
+            return None;
+        }
+        [self.clipboard_spool_dir(), self.stdin_spool_dir()]
+            .into_iter()
+            .find(|spool_dir| file_path.starts_with(spool_dir))
+    }
+
+
+    /// PID file locked by the running daemon
+    pub fn lock_file_path(&self) -> PathBuf {
+        self.project_dir().join("small.pid")
+    }
+
+
+    /// Unix socket of the control API of the running daemon
+    pub fn control_socket_path(&self) -> PathBuf {
+        self.project_dir().join("small.sock")
+    }
+
+
     pub fn default_config_file() -> PathBuf {
         Self::project_root_dir().join("config.toml")
     }
@@ -0,0 +1,265 @@
+use crate::{
+    config::SharedConfig,
+    database::{Database, PauseState},
+    reload::ConfigReloader,
+    supervisor::Supervisor,
+    *,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::{fs::PermissionsExt, net},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+};
+
+
+/// Command sent to the control socket, one JSON object per line,
+/// e.g. {"command":"force_config","name":"work"}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Status,
+    Queue,
+    Pause {
+        #[serde(default)]
+        until: Option<String>, // e.g. "30m" or "18:00", paused until resumed if None
+    },
+    Resume,
+    ForceConfig {
+        #[serde(default)]
+        name: Option<String>, // None brings the schedules back
+    },
+    Dump {
+        #[serde(default)]
+        path: Option<PathBuf>,
+    },
+    Reload,
+    Dumps,
+    Restore {
+        dump: PathBuf, // path, file name in the dumps directory or "latest"
+    },
+}
+
+
+/// Answer to every command, one JSON object per line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+
+impl ControlResponse {
+    fn from_result(result: Result<Value>) -> Self {
+        match result {
+            Ok(value) => {
+                ControlResponse {
+                    ok: true,
+                    result: Some(value),
+                    error: None,
+                }
+            }
+            Err(e) => {
+                ControlResponse {
+                    ok: false,
+                    result: None,
+                    error: Some(format!("{e:#}")),
+                }
+            }
+        }
+    }
+}
+
+
+/// Sends the request to the control socket of the running daemon
+pub fn send(socket_path: &Path, request: &ControlRequest) -> Result<Value> {
+    let mut stream = net::UnixStream::connect(socket_path).context(format!(
+        "Cannot connect to the control socket: {socket_path:?}"
+    ))?;
+    stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+    writeln!(stream, "{}", serde_json::to_string(request)?)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let response: ControlResponse =
+        serde_json::from_str(&line).context("Invalid control socket response")?;
+    match response.error {
+        Some(error) if !response.ok => anyhow::bail!(error),
+        _ => Ok(response.result.unwrap_or(Value::Null)),
+    }
+}
+
+
+/// The Unix socket control API of the running daemon
+#[derive(Debug)]
+pub struct ControlServer {
+    config: SharedConfig,
+    database: Arc<Database>,
+    supervisor: Supervisor,
+    started: Instant,
+}
+
+
+impl ControlServer {
+    pub fn new(config: SharedConfig, database: Arc<Database>, supervisor: Supervisor) -> Self {
+        ControlServer {
+            config,
+            database,
+            supervisor,
+            started: Instant::now(),
+        }
+    }
+
+
+    pub async fn start(self: Arc<Self>) -> Result<()> {
+        let socket_path = self.config.current().control_socket_path();
+        if socket_path.exists() {
+            if net::UnixStream::connect(&socket_path).is_ok() {
+                anyhow::bail!("Control socket: {socket_path:?} is used by another process");
+            }
+            fs::remove_file(&socket_path)?;
+        }
+        let listener = UnixListener::bind(&socket_path)
+            .context(format!("Cannot bind the control socket: {socket_path:?}"))?;
+        fs::set_permissions(&socket_path, fs::Permissions::from_mode(0o600))?;
+        info!("Control socket listening on: {socket_path:?}");
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve(stream).await {
+                    warn!("Control socket connection error: {e:?}");
+                }
+            });
+        }
+    }
+
+
+    /// Answers the requests of the connection. They're handled on the blocking threads:
+    /// the database calls block, the dumps and restores for a while.
+    async fn serve(self: Arc<Self>, stream: UnixStream) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let result = match serde_json::from_str::<ControlRequest>(&line)
+                .context("Invalid control request")
+            {
+                Ok(request) => {
+                    let server = self.clone();
+                    tokio::task::spawn_blocking(move || server.handle(request))
+                        .await
+                        .unwrap_or_else(|e| Err(e.into()))
+                }
+                Err(e) => Err(e),
+            };
+            let response = ControlResponse::from_result(result);
+            let mut answer = serde_json::to_string(&response)?;
+            answer.push('\n');
+            writer.write_all(answer.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+
+    pub fn handle(&self, request: ControlRequest) -> Result<Value> {
+        debug!("Control request: {request:?}");
+        match request {
+            ControlRequest::Status => self.status(),
+            ControlRequest::Queue => Ok(serde_json::to_value(self.database.get_queue()?)?),
+            ControlRequest::Pause {
+                until,
+            } => {
+                self.database
+                    .set_pause(&PauseState::until(until.as_deref())?)?;
+                self.status()
+            }
+            ControlRequest::Resume => {
+                self.database.set_pause(&PauseState::default())?;
+                self.status()
+            }
+            ControlRequest::ForceConfig {
+                name,
+            } => {
+                self.config.force_config(name.as_deref())?;
+                match &name {
+                    Some(name) => info!("Forced config: {name}"),
+                    None => info!("Selecting configs by the schedules again"),
+                }
+                self.status()
+            }
+            ControlRequest::Dump {
+                path,
+            } => {
+                let path = match path {
+                    Some(path) => {
+                        self.database.dump_to_file(&path)?;
+                        path
+                    }
+                    None => {
+                        let app_config = self.config.current();
+                        dumps::create(
+                            &self.database,
+                            &app_config.db_dumps_dir(),
+                            &app_config.dumps,
+                            false,
+                        )?
+                        .context("Database dump skipped")?
+                    }
+                };
+                Ok(json!({ "path": path }))
+            }
+            ControlRequest::Reload => {
+                ConfigReloader::new(self.config.clone()).reload()?;
+                self.status()
+            }
+            ControlRequest::Dumps => {
+                Ok(serde_json::to_value(dumps::inspect_all(
+                    &self.config.current().db_dumps_dir(),
+                )?)?)
+            }
+            ControlRequest::Restore {
+                dump,
+            } => {
+                let dump = dumps::resolve(&self.config.current().db_dumps_dir(), &dump)?;
+                let backup = self.database.restore_from_file(&dump)?;
+                Ok(json!({ "restored": dump, "backup": backup }))
+            }
+        }
+    }
+
+
+    fn status(&self) -> Result<Value> {
+        let app_config = self.config.current();
+        let active_config = app_config.select_config()?;
+        let pause = self.database.get_pause()?;
+        Ok(json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "pid": std::process::id(),
+            "uptime": self.started.elapsed().as_secs(),
+            "env": app_config.env,
+            "config_file": app_config.config_file,
+            "active_config": active_config.name,
+            "forced_config": app_config.forced_config,
+            "paused": pause.is_active(chrono::Local::now().timestamp()),
+            "paused_until": pause.until_local(),
+            "queued": self.database.queue_length()?,
+            "subsystems": self.supervisor.statuses(),
+        }))
+    }
+}
@@ -0,0 +1,314 @@
+use crate::{
+    config::{AppConfig, SharedConfig},
+    database::Database,
+    supervisor::Supervisor,
+    *,
+};
+use anyhow::{Context, Result};
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    time,
+};
+use tokio_util::sync::CancellationToken;
+
+
+/// Why the daemon stopped. Decides the exit status, so launchd (KeepAlive with
+/// SuccessfulExit = false) and systemd (Restart = on-failure) know whether to restart it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Shutdown {
+    /// SIGINT or SIGTERM, exits with 0 and stays stopped
+    Stop,
+    /// One of the subsystems stopped, exits with 1 to be started again
+    Failure,
+}
+
+
+impl Shutdown {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Shutdown::Stop => 0,
+            Shutdown::Failure => 1,
+        }
+    }
+}
+
+
+/// Runs all the subsystems until a signal or until one of them stops, SIGHUP reloads the config.
+/// On shutdown the watchers stop first, the upload in progress gets the shutdown_timeout
+/// to finish (or it's resumed on the next start) and the database is flushed.
+pub async fn run(app_config: AppConfig) -> Result<Shutdown> {
+    // Only one daemon per environment, held until the process exits
+    let _instance_lock = InstanceLock::acquire(&app_config.lock_file_path())?;
+
+    let shared_config = SharedConfig::new(app_config);
+    let config = shared_config.current();
+    let version = env!("CARGO_PKG_VERSION");
+
+    info!("Launching Small v{version}");
+    config.log_runtime_settings();
+
+    // Send startup notification
+    if let Err(e) = notification::notification(
+        &format!("Launching SmallApplication v{version}"),
+        "start",
+        &config.notifications,
+        &config.sounds,
+    ) {
+        warn!("Failed to send startup notification: {e:?}");
+    }
+
+    // Initialize database
+    let db_path = config.database_path();
+    let database = Arc::new(Database::new(&db_path)?);
+    info!("Initializing database backend: {db_path:?}");
+
+    // Restarts the failed subsystems, the daemon stops when it gives up on a core one
+    let shutdown = CancellationToken::new();
+    let supervisor = Supervisor::new(shutdown.clone(), config.max_restarts);
+
+    // Start SFTP manager
+    let (sftp_manager, _rx) = sftp::SftpManager::new(shared_config.clone(), database.clone());
+    let sftp_manager = Arc::new(sftp_manager);
+    let mut sftp_handle = {
+        let shutdown = shutdown.clone();
+        supervisor.spawn("SFTP manager", move || {
+            let (sftp_manager, shutdown) = (sftp_manager.clone(), shutdown.clone());
+            async move {
+                sftp_manager.start(shutdown).await;
+                Ok(())
+            }
+        })
+    };
+
+    // Start web API
+    let web_api = Arc::new(webapi::WebApi::new(
+        shared_config.clone(),
+        database.clone(),
+        supervisor.clone(),
+    ));
+    let web_handle = supervisor.spawn("Web API", move || web_api.clone().start());
+
+    // Open browser if configured
+    if config.open_history_on_start {
+        info!(
+            "Automatically opening http dashboard: http://localhost:{} in default browser.",
+            config.webapi_port
+        );
+        if let Err(e) = std::process::Command::new("open")
+            .arg(format!("http://localhost:{}", config.webapi_port))
+            .spawn()
+        {
+            warn!("Failed to open browser: {e:?}");
+        }
+    }
+
+    // Start file watcher
+    let file_watcher = Arc::new(watcher::FileWatcher::new(
+        shared_config.clone(),
+        database.clone(),
+    ));
+    let watcher_handle = {
+        let shutdown = shutdown.clone();
+        supervisor.spawn("File watcher", move || {
+            file_watcher.clone().start(shutdown.clone())
+        })
+    };
+
+    // Start clipboard images watcher (opt-in, doesn't stop the eternal watch)
+    let clipboard_watcher = Arc::new(clipboard::ClipboardWatcher::new(
+        shared_config.clone(),
+        database.clone(),
+    ));
+    {
+        let shutdown = shutdown.clone();
+        supervisor.spawn("Clipboard watcher", move || {
+            clipboard_watcher.clone().start(shutdown.clone())
+        });
+    }
+
+    // Start config.toml hot reload (doesn't stop the eternal watch)
+    let config_reloader = Arc::new(reload::ConfigReloader::new(shared_config.clone()));
+    {
+        let config_reloader = config_reloader.clone();
+        supervisor.spawn("Config reloader", move || config_reloader.clone().start());
+    }
+
+    // Start the control socket (doesn't stop the eternal watch)
+    let control_server = Arc::new(control::ControlServer::new(
+        shared_config.clone(),
+        database.clone(),
+        supervisor.clone(),
+    ));
+    supervisor.spawn("Control socket", move || control_server.clone().start());
+
+    // Start periodic database dumper
+    let dump_interval = config.db_autodump_interval;
+    let periodic_dump_handle = {
+        let database = database.clone();
+        let shared_config = shared_config.clone();
+        let dumps_dir = config.db_dumps_dir();
+        info!(
+            "Initializing periodic dumper (triggered every {} hours)",
+            dump_interval / 3600000
+        );
+        match dumps::disk_usage(&dumps_dir) {
+            Ok((count, size)) => {
+                info!(
+                    "Database dumps: {count} in: {dumps_dir:?}, using: {}",
+                    human_size(size)
+                )
+            }
+            Err(e) => warn!("Cannot read the database dumps: {dumps_dir:?}: {e}"),
+        }
+        supervisor.spawn("Periodic dump", move || {
+            periodic_dump(database.clone(), shared_config.clone(), dump_interval)
+        })
+    };
+
+    info!("Starting an eternal watch…");
+
+    // Wait for a signal or for the supervisor giving up on a core subsystem
+    let stop = tokio::select! {
+        signal = shutdown_signal(&config_reloader) => signal?,
+        _ = &mut sftp_handle => Shutdown::Failure,
+        _ = web_handle => Shutdown::Failure,
+        _ = watcher_handle => Shutdown::Failure,
+        _ = periodic_dump_handle => Shutdown::Failure,
+    };
+    shutdown.cancel();
+
+    if !sftp_handle.is_finished() {
+        let timeout = config.shutdown_timeout;
+        info!("Waiting up to {timeout}ms for the upload in progress");
+        if time::timeout(Duration::from_millis(timeout), sftp_handle)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Upload still in progress after {timeout}ms, it's resumed on the next start"
+            );
+        }
+    }
+
+    if let Err(e) = database.call(|database| database.flush()).await {
+        error!("Failed to flush the database: {e:?}");
+    }
+    fs::remove_file(config.control_socket_path()).ok();
+    info!("Small stopped ({stop:?}), exit code: {}", stop.exit_code());
+    Ok(stop)
+}
+
+
+/// Dumps the database every interval (ms), the first dump right away.
+/// The dumps settings of the current config are used, so they can be reloaded.
+async fn periodic_dump(
+    database: Arc<Database>,
+    shared_config: SharedConfig,
+    interval: u64,
+) -> Result<()> {
+    let mut interval = time::interval(Duration::from_millis(interval));
+    loop {
+        interval.tick().await;
+        let config = shared_config.current();
+        let dumps_settings = config.dumps;
+        let dumped = database
+            .call(move |database| {
+                dumps::create(
+                    database,
+                    &config.db_dumps_dir(),
+                    &dumps_settings,
+                    dumps_settings.skip_unchanged,
+                )
+            })
+            .await;
+        if let Err(e) = dumped {
+            error!("Failed to dump database: {e:?}");
+        }
+    }
+}
+
+
+/// Waits for SIGINT or SIGTERM. SIGHUP reloads the config.toml, like: small reload
+async fn shutdown_signal(config_reloader: &reload::ConfigReloader) -> Result<Shutdown> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            _ = interrupt.recv() => {
+                info!("Received SIGINT, shutting down");
+                return Ok(Shutdown::Stop);
+            }
+            _ = terminate.recv() => {
+                info!("Received SIGTERM, shutting down");
+                return Ok(Shutdown::Stop);
+            }
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading the configuration");
+                // NOTE: an invalid config.toml is reported, the current one stays active
+                config_reloader.reload().ok();
+            }
+        }
+    }
+}
+
+
+/// Exclusive lock of the PID file, released by the OS when the process exits,
+/// so a lock left by a crashed daemon doesn't block the next one
+#[derive(Debug)]
+pub struct InstanceLock {
+    file: File,
+}
+
+
+impl InstanceLock {
+    pub fn acquire(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .context(format!("Cannot open the lock file: {path:?}"))?;
+
+        match file.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid).ok();
+                anyhow::bail!(
+                    "Small is already running (PID: {}), lock file: {path:?}",
+                    pid.trim()
+                );
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).context(format!("Cannot lock the lock file: {path:?}"));
+            }
+        }
+
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+        debug!("Acquired the instance lock: {path:?}");
+        Ok(InstanceLock {
+            file,
+        })
+    }
+}
+
+
+/// True when a daemon answers on the control socket of the configuration
+pub fn is_running(app_config: &AppConfig) -> bool {
+    UnixStream::connect(app_config.control_socket_path()).is_ok()
+}
@@ -1,31 +1,322 @@
-use crate::*;
-use anyhow::Result;
-use rusqlite::{Connection, params};
+use crate::{config::Config, dumps, migrations, schedule, *};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use rusqlite::{Connection, OpenFlags, Row, params, params_from_iter, types::Value};
 use serde::{Deserialize, Serialize};
 use std::{
-    path::Path,
-    sync::{Arc, Mutex},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
 };
 
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct History {
     pub content: String,
     pub timestamp: i64,
     pub file: String,
     pub uuid: String,
+    pub config: Option<String>, // name of the destination config
+    // NOTE: the upload metadata is missing in the entries of the older versions
+    pub size: Option<u64>,           // bytes
+    pub mime: Option<String>,        // guessed by the file extension
+    pub sha256: Option<String>,      // hex encoded hash of the content
+    pub width: Option<u32>,          // of the images only
+    pub height: Option<u32>,         // of the images only
+    pub upload_ms: Option<u64>,      // duration of the upload
+    pub throughput: Option<f64>,     // bytes sent per second
+    pub remote_file: Option<String>, // path on the destination host
+    pub backend: Option<String>,     // e.g. "sftp"
+    #[serde(default)]
+    pub tags: Vec<String>, // normalized by normalize_tags
+    pub note: Option<String>,
 }
 
+
+/// Columns read by History::from_row
+const HISTORY_COLUMNS: &str = "content, timestamp, file, uuid, config, size, mime, sha256, width, height, upload_ms, throughput, remote_file, backend, tags, note";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QueueItem {
     pub local_file: String,
     pub remote_file: String,
     pub uuid: String,
+    pub config: Option<String>, // name of the destination config, the scheduled one if None
+}
+
+/// Uploading pause, kept in the settings table so it survives restarts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PauseState {
+    pub paused: bool,
+    pub until: Option<i64>, // timestamp of the automatic resume, paused until resumed if None
+}
+
+impl PauseState {
+    /// Pause until the end parsed by: schedule::parse_until, or until resumed
+    pub fn until(until: Option<&str>) -> Result<Self> {
+        let until = match until {
+            Some(until) => {
+                let now = Local::now().naive_local();
+                let end = schedule::parse_until(until, now)?;
+                Some(
+                    end.and_local_timezone(Local)
+                        .earliest()
+                        .context(format!("Invalid local time: {end}"))?
+                        .timestamp(),
+                )
+            }
+            None => None,
+        };
+        Ok(PauseState {
+            paused: true,
+            until,
+        })
+    }
+
+
+    /// Local time of the automatic resume
+    pub fn until_local(&self) -> Option<String> {
+        self.until
+            .and_then(|until| DateTime::from_timestamp(until, 0))
+            .map(|until| {
+                DateTime::<Local>::from(until)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+    }
+
+
+    /// True when uploading is paused at the timestamp
+    pub fn is_active(&self, now: i64) -> bool {
+        self.paused && self.until.is_none_or(|until| now < until)
+    }
+}
+
+impl QueueItem {
+    /// Queue item of the local file for the destination config.
+    /// The uuid is derived from the file path.
+    pub fn new(local_file: &str, config: &Config) -> Self {
+        let uuid =
+            uuid::Uuid::new_v3(&uuid::Uuid::NAMESPACE_OID, local_file.as_bytes()).to_string();
+        QueueItem {
+            local_file: local_file.to_string(),
+            remote_file: format!("{}/{uuid}", config.remote_path),
+            uuid,
+            config: Some(config.name.clone()),
+        }
+    }
+
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(QueueItem {
+            local_file: row.get(0)?,
+            remote_file: row.get(1)?,
+            uuid: row.get(2)?,
+            config: row.get(3)?,
+        })
+    }
+}
+
+impl History {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(History {
+            content: row.get(0)?,
+            timestamp: row.get(1)?,
+            file: row.get(2)?,
+            uuid: row.get(3)?,
+            config: row.get(4)?,
+            size: row.get(5)?,
+            mime: row.get(6)?,
+            sha256: row.get(7)?,
+            width: row.get(8)?,
+            height: row.get(9)?,
+            upload_ms: row.get(10)?,
+            throughput: row.get(11)?,
+            remote_file: row.get(12)?,
+            backend: row.get(13)?,
+            tags: row
+                .get::<_, Option<String>>(14)?
+                .map(|tags| normalize_tags(&[tags]))
+                .unwrap_or_default(),
+            note: row.get(15)?,
+        })
+    }
+
+
+    /// Cursor of the next page, when this entry is the last one of the page
+    pub fn cursor(&self) -> HistoryCursor {
+        HistoryCursor {
+            timestamp: self.timestamp,
+            uuid: self.uuid.clone(),
+        }
+    }
+
+
+    /// Local time of the upload, e.g. "2025-09-01 18:00:00"
+    pub fn local_time(&self) -> String {
+        DateTime::from_timestamp(self.timestamp, 0)
+            .map(|dt_utc| {
+                DateTime::<Local>::from(dt_utc)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_else(|| self.timestamp.to_string())
+    }
+
+
+    /// Upload metadata for humans, e.g. "1.25MiB, image/png, 1920x1080, 0.42s at 2.98MiB/s"
+    pub fn details(&self) -> String {
+        let mut details = Vec::new();
+        if let Some(size) = self.size {
+            details.push(human_size(size));
+        }
+        if let Some(mime) = &self.mime {
+            details.push(mime.clone());
+        }
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            details.push(format!("{width}x{height}"));
+        }
+        match (self.upload_ms, self.throughput) {
+            (Some(upload_ms), Some(throughput)) => {
+                details.push(format!(
+                    "{:.2}s at {}/s",
+                    upload_ms as f64 / 1000.0,
+                    human_size(throughput as u64)
+                ))
+            }
+            (Some(upload_ms), None) => {
+                details.push(format!("{:.2}s", upload_ms as f64 / 1000.0))
+            }
+            _ => (),
+        }
+        if let Some(remote_file) = &self.remote_file {
+            let backend = self.backend.as_deref().unwrap_or("remote");
+            details.push(format!("{backend}:{remote_file}"));
+        }
+        if let Some(sha256) = &self.sha256 {
+            details.push(format!("sha256:{}", &sha256[..sha256.len().min(12)]));
+        }
+        details.join(", ")
+    }
+}
+
+/// The text matched literally by a LIKE pattern with ESCAPE '\'
+fn like_escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+
+/// Lowercase tags without the "#", separated by commas or whitespace, without duplicates
+pub fn normalize_tags<S: AsRef<str>>(tags: &[S]) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags
+        .iter()
+        .flat_map(|tags| tags.as_ref().split(|c: char| c == ',' || c.is_whitespace()))
+        .map(|tag| tag.trim_start_matches('#').to_lowercase())
+    {
+        if !tag.is_empty() && !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    normalized
+}
+
+
+/// Position of the history entry in the listing, ordered by the timestamp and uuid
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryCursor {
+    pub timestamp: i64,
+    pub uuid: String,
+}
+
+
+/// Column a history entry is looked up by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryKey {
+    Uuid,
+    Content,
+    Sha256,
+    File,
+}
+
+
+/// Search of the history entries, all given conditions have to match
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryFilter {
+    pub text: Option<String>, // words (or their beginnings) of the file name, link, config, tags or note
+    pub since: Option<i64>,   // timestamp
+    pub until: Option<i64>,   // timestamp
+    pub kind: Option<String>, // MIME type "image/png", its type "image" or file extension "png"
+    pub tag: Option<String>,
+    pub after: Option<String>, // uuid of the entry the listing continues after, a keyset page
+    pub limit: usize,
+}
+
+
+impl HistoryFilter {
+    /// Filter of the date range bounds "7d", "2025-09-01" or "2025-09-01 18:00" (local time)
+    pub fn new(
+        text: Option<&str>,
+        since: Option<&str>,
+        until: Option<&str>,
+        kind: Option<&str>,
+        tag: Option<&str>,
+        limit: usize,
+    ) -> Result<Self> {
+        let now = Local::now().naive_local();
+        let bound = |text: &str, end: bool| -> Result<i64> {
+            let date = schedule::parse_date_bound(text, now, end)?;
+            Ok(date
+                .and_local_timezone(Local)
+                .earliest()
+                .context(format!("Invalid local time: {date}"))?
+                .timestamp())
+        };
+        Ok(HistoryFilter {
+            text: text.map(String::from),
+            since: since.map(|since| bound(since, false)).transpose()?,
+            until: until.map(|until| bound(until, true)).transpose()?,
+            kind: kind.map(String::from),
+            tag: tag.and_then(|tag| normalize_tags(&[tag]).into_iter().next()),
+            after: None,
+            limit,
+        })
+    }
+
+
+    /// FTS5 query matching the beginnings of all the words, None without any words
+    fn fts_query(text: &str) -> Option<String> {
+        let words: Vec<String> = text
+            .split_whitespace()
+            .filter(|word| word.chars().any(char::is_alphanumeric))
+            .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+            .collect();
+        (!words.is_empty()).then(|| words.join(" "))
+    }
 }
 
+
+/// Amount of the read-only connections of the database
+const READERS: usize = 4;
+
+/// How long a connection waits for the lock of another one before failing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+
+/// The database in the WAL mode: one connection writes while the readers read the
+/// last committed content, so the dashboard queries never wait for the uploads.
+/// NOTE: the calls block, the async code runs them by: Database::call
 #[derive(Debug)]
 pub struct Database {
-    conn: Arc<Mutex<Connection>>,
+    writer: Mutex<Connection>,
+    readers: Vec<Mutex<Connection>>,
+    next_reader: AtomicUsize,
+    path: PathBuf,
 }
 
 impl Database {
@@ -35,126 +326,511 @@ impl Database {
             std::fs::create_dir_all(parent)?;
         }
 
-        let conn = Connection::open(db_path)?;
-        let db = Database {
-            conn: Arc::new(Mutex::new(conn)),
+        let writer = Self::open(&db_path)?;
+        writer.pragma_update_and_check(None, "journal_mode", "WAL", |row| {
+            row.get::<_, String>(0)
+        })?;
+        writer.pragma_update(None, "synchronous", "NORMAL")?;
+        let mut db = Database {
+            writer: Mutex::new(writer),
+            readers: Vec::new(),
+            next_reader: AtomicUsize::new(0),
+            path: db_path.as_ref().to_path_buf(),
         };
-        db.init_schema()?;
+        db.migrate(true)?;
+        for _ in 0..READERS {
+            let reader = Self::open(&db_path)?;
+            reader.pragma_update(None, "query_only", true)?;
+            db.readers.push(Mutex::new(reader));
+        }
         Ok(db)
     }
 
 
-    fn init_schema(&self) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
+    fn open<P: AsRef<Path>>(db_path: P) -> Result<Connection> {
+        let conn = Connection::open(&db_path)
+            .context(format!("Cannot open the database: {:?}", db_path.as_ref()))?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(conn)
+    }
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS history (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                content TEXT NOT NULL,
-                timestamp INTEGER NOT NULL,
-                file TEXT NOT NULL,
-                uuid TEXT NOT NULL UNIQUE
-            )",
-            [],
-        )?;
 
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_history_timestamp ON history(timestamp)",
-            [],
-        )?;
+    /// Runs the calls on the blocking threads of Tokio, so they never stall the async tasks
+    pub async fn call<T, F>(self: &Arc<Self>, call: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database) -> Result<T> + Send + 'static,
+    {
+        let database = self.clone();
+        tokio::task::spawn_blocking(move || call(&database))
+            .await
+            .context("Database call failed")?
+    }
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS queue (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                local_file TEXT NOT NULL,
-                remote_file TEXT NOT NULL,
-                uuid TEXT NOT NULL UNIQUE
-            )",
-            [],
-        )?;
 
-        Ok(())
+    fn writer(&self) -> MutexGuard<'_, Connection> {
+        self.writer.lock().unwrap()
+    }
+
+
+    /// The first idle reader, or the next one in turn when all of them are busy.
+    /// The writer reads while the database is migrated, before the readers are opened.
+    fn reader(&self) -> MutexGuard<'_, Connection> {
+        if self.readers.is_empty() {
+            return self.writer();
+        }
+        for reader in &self.readers {
+            if let Ok(reader) = reader.try_lock() {
+                return reader;
+            }
+        }
+        let next = self.next_reader.fetch_add(1, Ordering::Relaxed) % self.readers.len();
+        self.readers[next].lock().unwrap()
+    }
+
+
+    /// Brings the schema up to date, backing up the database next to it first
+    fn migrate(&self, backup: bool) -> Result<()> {
+        let (pending, version, empty) = {
+            let conn = self.writer();
+            (
+                migrations::pending(&conn)?,
+                migrations::user_version(&conn)?,
+                migrations::is_empty(&conn)?,
+            )
+        };
+        if pending.is_empty() {
+            return Ok(());
+        }
+        if backup && !empty {
+            self.dump_to_file(self.backup_path(&format!("v{version}")))
+                .context("Cannot back up the database before the migration")?;
+        }
+        let mut conn = self.writer();
+        migrations::apply(&mut conn, &pending)
+    }
+
+
+    /// Timestamped path of a backup next to the database, e.g. small.db.v2.<timestamp>.bak
+    fn backup_path(&self, label: &str) -> PathBuf {
+        let timestamp = Local::now().format("%Y-%m-%d-%H-%M-%S");
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{label}.{timestamp}.bak"));
+        PathBuf::from(name)
+    }
+
+
+    /// Runs the calls of the connection functions (e.g. Database::insert_history)
+    /// in one transaction of the writer, rolled back when the run fails
+    pub fn transaction<T, F>(&self, run: F) -> Result<T>
+    where
+        F: FnOnce(&Connection) -> Result<T>,
+    {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        let result = run(&tx)?;
+        tx.commit()?;
+        Ok(result)
     }
 
 
     pub fn add_to_queue(&self, item: &QueueItem) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "INSERT OR IGNORE INTO queue (local_file, remote_file, uuid) VALUES (?1, ?2, ?3)",
-            params![&item.local_file, &item.remote_file, &item.uuid],
-        )?;
+        Self::insert_queue_item(&self.writer(), item)?;
         Ok(())
     }
 
 
+    /// Adds the item to the queue of the connection, returns 0 when it was already queued
+    pub fn insert_queue_item(conn: &Connection, item: &QueueItem) -> Result<usize> {
+        let added = conn.execute(
+            "INSERT OR IGNORE INTO queue (local_file, remote_file, uuid, config) VALUES (?1, ?2, ?3, ?4)",
+            params![&item.local_file, &item.remote_file, &item.uuid, &item.config],
+        )?;
+        Ok(added)
+    }
+
+
     pub fn get_queue(&self) -> Result<Vec<QueueItem>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT local_file, remote_file, uuid FROM queue")?;
+        let conn = self.reader();
+        let mut stmt =
+            conn.prepare("SELECT local_file, remote_file, uuid, config FROM queue")?;
         let items = stmt
-            .query_map([], |row| {
-                Ok(QueueItem {
-                    local_file: row.get(0)?,
-                    remote_file: row.get(1)?,
-                    uuid: row.get(2)?,
-                })
-            })?
+            .query_map([], QueueItem::from_row)?
             .collect::<Result<Vec<_>, _>>()?;
         Ok(items)
     }
 
 
-    pub fn remove_from_queue(&self, uuid: &str) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute("DELETE FROM queue WHERE uuid = ?1", params![uuid])?;
+    pub fn remove_from_queue(&self, uuid: &str) -> Result<usize> {
+        let conn = self.writer();
+        let removed = conn.execute("DELETE FROM queue WHERE uuid = ?1", params![uuid])?;
+        Ok(removed)
+    }
+
+
+    pub fn remove_from_queue_by_file(&self, local_file: &str) -> Result<usize> {
+        let conn = self.writer();
+        let removed = conn.execute(
+            "DELETE FROM queue WHERE local_file = ?1",
+            params![local_file],
+        )?;
+        Ok(removed)
+    }
+
+
+    /// Records the size and modification time of the local file an upload started with
+    pub fn start_upload(&self, uuid: &str, size: u64, mtime: i64) -> Result<()> {
+        let conn = self.writer();
+        conn.execute(
+            "UPDATE queue SET upload_size = ?2, upload_mtime = ?3 WHERE uuid = ?1",
+            params![uuid, size as i64, mtime],
+        )?;
         Ok(())
     }
 
 
+    /// Size and modification time of the local file of the interrupted upload
+    pub fn upload_checkpoint(&self, uuid: &str) -> Result<Option<(u64, i64)>> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare(
+            "SELECT upload_size, upload_mtime FROM queue WHERE uuid = ?1 AND upload_size IS NOT NULL AND upload_mtime IS NOT NULL",
+        )?;
+        let mut checkpoints = stmt.query_map(params![uuid], |row| {
+            Ok((row.get::<_, i64>(0)? as u64, row.get(1)?))
+        })?;
+        Ok(checkpoints.next().transpose()?)
+    }
+
+
+    pub fn queue_length(&self) -> Result<usize> {
+        let conn = self.reader();
+        let length: i64 =
+            conn.query_row("SELECT COUNT(*) FROM queue", [], |row| row.get(0))?;
+        Ok(length as usize)
+    }
+
+
+    pub fn clear_queue(&self) -> Result<usize> {
+        let conn = self.writer();
+        let removed = conn.execute("DELETE FROM queue", [])?;
+        Ok(removed)
+    }
+
+
+    /// Replaces pending uploads of the old file with the given item.
+    /// Returns true if there was anything pending for the old file.
+    pub fn move_in_queue(&self, old_local_file: &str, item: &QueueItem) -> Result<bool> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        let moved = tx.execute(
+            "DELETE FROM queue WHERE local_file = ?1",
+            params![old_local_file],
+        )?;
+        tx.execute(
+            "INSERT OR IGNORE INTO queue (local_file, remote_file, uuid, config) VALUES (?1, ?2, ?3, ?4)",
+            params![&item.local_file, &item.remote_file, &item.uuid, &item.config],
+        )?;
+        tx.commit()?;
+        Ok(moved > 0)
+    }
+
+
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare("SELECT value FROM settings WHERE key = ?1")?;
+        let mut values = stmt.query_map(params![key], |row| row.get(0))?;
+        Ok(values.next().transpose()?)
+    }
+
+
+    /// Stores the setting, removes it when the value is None
+    pub fn set_setting(&self, key: &str, value: Option<&str>) -> Result<()> {
+        let conn = self.writer();
+        match value {
+            Some(value) => {
+                conn.execute(
+                    "INSERT INTO settings (key, value) VALUES (?1, ?2) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                    params![key, value],
+                )?
+            }
+            None => conn.execute("DELETE FROM settings WHERE key = ?1", params![key])?,
+        };
+        Ok(())
+    }
+
+
+    pub fn get_pause(&self) -> Result<PauseState> {
+        Ok(PauseState {
+            paused: self.get_setting("paused")?.as_deref() == Some("true"),
+            until: self
+                .get_setting("paused_until")?
+                .and_then(|until| until.parse().ok()),
+        })
+    }
+
+
+    pub fn set_pause(&self, pause: &PauseState) -> Result<()> {
+        self.set_setting("paused", Some(&pause.paused.to_string()))?;
+        self.set_setting(
+            "paused_until",
+            pause.until.map(|until| until.to_string()).as_deref(),
+        )
+    }
+
+
     pub fn add_history(&self, history: &History) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
+        Self::insert_history(&self.writer(), history)
+    }
+
+
+    pub fn insert_history(conn: &Connection, history: &History) -> Result<()> {
         conn.execute(
-            "INSERT OR IGNORE INTO history (content, timestamp, file, uuid) VALUES (?1, ?2, ?3, ?4)",
-            params![&history.content, &history.timestamp, &history.file, &history.uuid],
+            &format!(
+                "INSERT OR IGNORE INTO history ({HISTORY_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)"
+            ),
+            params![
+                &history.content,
+                &history.timestamp,
+                &history.file,
+                &history.uuid,
+                &history.config,
+                &history.size,
+                &history.mime,
+                &history.sha256,
+                &history.width,
+                &history.height,
+                &history.upload_ms,
+                &history.throughput,
+                &history.remote_file,
+                &history.backend,
+                &history.tags.join(","),
+                &history.note
+            ],
         )?;
         Ok(())
     }
 
 
+    /// Latest history entries, all of them when the limit is None
     pub fn get_history(&self, limit: Option<usize>) -> Result<Vec<History>> {
-        let conn = self.conn.lock().unwrap();
-        let query = if let Some(lim) = limit {
-            format!(
-                "SELECT content, timestamp, file, uuid FROM history ORDER BY timestamp DESC LIMIT {}",
-                lim
-            )
+        self.search_history(&HistoryFilter {
+            limit: limit.unwrap_or(usize::MAX),
+            ..HistoryFilter::default()
+        })
+    }
+
+
+    /// Latest history entries matching the filter, the newest first
+    pub fn search_history(&self, filter: &HistoryFilter) -> Result<Vec<History>> {
+        let mut conditions = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+        if let Some(query) = filter.text.as_deref().and_then(HistoryFilter::fts_query) {
+            conditions.push("id IN (SELECT rowid FROM history_fts WHERE history_fts MATCH ?)");
+            values.push(Value::Text(query));
+        }
+        if let Some(since) = filter.since {
+            conditions.push("timestamp >= ?");
+            values.push(Value::Integer(since));
+        }
+        if let Some(until) = filter.until {
+            conditions.push("timestamp <= ?");
+            values.push(Value::Integer(until));
+        }
+        if let Some(kind) = filter.kind.as_deref() {
+            let kind = kind.trim().trim_start_matches('.').to_lowercase();
+            if kind.contains('/') {
+                conditions.push("mime = ?");
+                values.push(Value::Text(kind));
+            } else {
+                // NOTE: the % and _ of the type are matched literally
+                conditions.push(
+                    r"(mime LIKE ? || '/%' ESCAPE '\' OR lower(file) LIKE '%.' || ? ESCAPE '\')",
+                );
+                let kind = like_escape(&kind);
+                values.push(Value::Text(kind.clone()));
+                values.push(Value::Text(kind));
+            }
+        }
+        if let Some(tag) = &filter.tag {
+            conditions.push("instr(',' || tags || ',', ',' || ? || ',') > 0");
+            values.push(Value::Text(tag.clone()));
+        }
+        // NOTE: the keyset page doesn't count the skipped entries like an offset does
+        if let Some(after) = &filter.after {
+            let cursor = self
+                .find_history(after)?
+                .context(format!("No history entry: {after}"))?
+                .cursor();
+            conditions.push("(timestamp, uuid) < (?, ?)");
+            values.push(Value::Integer(cursor.timestamp));
+            values.push(Value::Text(cursor.uuid));
+        }
+        let conditions = if conditions.is_empty() {
+            String::new()
         } else {
-            "SELECT content, timestamp, file, uuid FROM history ORDER BY timestamp DESC"
-                .to_string()
+            format!("WHERE {}", conditions.join(" AND "))
         };
+        values.push(Value::Integer(
+            i64::try_from(filter.limit).unwrap_or(i64::MAX),
+        ));
 
-        let mut stmt = conn.prepare(&query)?;
+        let conn = self.reader();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {HISTORY_COLUMNS} FROM history {conditions} ORDER BY timestamp DESC, uuid DESC LIMIT ?"
+        ))?;
         let items = stmt
-            .query_map([], |row| {
-                Ok(History {
-                    content: row.get(0)?,
-                    timestamp: row.get(1)?,
-                    file: row.get(2)?,
-                    uuid: row.get(3)?,
-                })
-            })?
+            .query_map(params_from_iter(values), History::from_row)?
             .collect::<Result<Vec<_>, _>>()?;
         Ok(items)
     }
 
 
+    /// Replaces the tags of the history entry, false when there's no such entry
+    pub fn set_tags(&self, uuid: &str, tags: &[String]) -> Result<bool> {
+        Self::update_tags(&self.writer(), uuid, tags)
+    }
+
+
+    pub fn update_tags(conn: &Connection, uuid: &str, tags: &[String]) -> Result<bool> {
+        let updated = conn.execute(
+            "UPDATE history SET tags = ?1 WHERE uuid = ?2",
+            params![normalize_tags(tags).join(","), uuid],
+        )?;
+        Ok(updated > 0)
+    }
+
+
+    /// Replaces the note of the history entry, false when there's no such entry
+    pub fn set_note(&self, uuid: &str, note: Option<&str>) -> Result<bool> {
+        Self::update_note(&self.writer(), uuid, note)
+    }
+
+
+    pub fn update_note(conn: &Connection, uuid: &str, note: Option<&str>) -> Result<bool> {
+        let note = note.map(str::trim).filter(|note| !note.is_empty());
+        let updated = conn.execute(
+            "UPDATE history SET note = ?1 WHERE uuid = ?2",
+            params![note, uuid],
+        )?;
+        Ok(updated > 0)
+    }
+
+
+    pub fn find_history(&self, uuid: &str) -> Result<Option<History>> {
+        Self::query_history(&self.reader(), HistoryKey::Uuid, uuid)
+    }
+
+
+    /// History entry of the link, which is unique
+    pub fn find_history_by_content(&self, content: &str) -> Result<Option<History>> {
+        Self::query_history(&self.reader(), HistoryKey::Content, content)
+    }
+
+
+    /// Latest history entry of the file content, for the entries with the upload metadata
+    pub fn find_history_by_sha256(&self, sha256: &str) -> Result<Option<History>> {
+        Self::query_history(&self.reader(), HistoryKey::Sha256, sha256)
+    }
+
+
+    pub fn find_history_by_file(&self, file: &str) -> Result<Option<History>> {
+        Self::query_history(&self.reader(), HistoryKey::File, file)
+    }
+
+
+    /// Latest history entry of the connection with the value of the key
+    pub fn query_history(
+        conn: &Connection,
+        key: HistoryKey,
+        value: &str,
+    ) -> Result<Option<History>> {
+        let column = match key {
+            HistoryKey::Uuid => "uuid",
+            HistoryKey::Content => "content",
+            HistoryKey::Sha256 => "sha256",
+            HistoryKey::File => "file",
+        };
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {HISTORY_COLUMNS} FROM history WHERE {column} = ?1 ORDER BY timestamp DESC, uuid DESC LIMIT 1"
+        ))?;
+        let mut items = stmt.query_map(params![value], History::from_row)?;
+        Ok(items.next().transpose()?)
+    }
+
+
     pub fn dump_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.reader();
         let mut backup_conn = Connection::open(&path)?;
 
-        let backup = rusqlite::backup::Backup::new(&conn, &mut backup_conn)?;
-        backup.run_to_completion(5, std::time::Duration::from_millis(250), None)?;
+        {
+            let backup = rusqlite::backup::Backup::new(&conn, &mut backup_conn)?;
+            backup.run_to_completion(5, Duration::from_millis(250), None)?;
+        }
+        // NOTE: the copy is a single file, not in the WAL mode of the database
+        backup_conn.pragma_update_and_check(None, "journal_mode", "DELETE", |row| {
+            row.get::<_, String>(0)
+        })?;
 
         info!("Database dumped to: {:?}", path.as_ref());
         Ok(())
     }
+
+
+    /// Writes everything to the database file before the process exits
+    pub fn flush(&self) -> Result<()> {
+        let conn = self.writer();
+        conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE); PRAGMA optimize;")?;
+        debug!("Database flushed");
+        Ok(())
+    }
+
+
+    /// Replaces the content of the database with the dump, gzipped or not, while it's used.
+    /// The dump is checked first and the current database is backed up next to it.
+    /// Returns the path of the backup.
+    pub fn restore_from_file<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            anyhow::bail!("No database dump: {path:?}");
+        }
+        if dumps::is_compressed(path) {
+            let decompressed = dumps::decompress(path)?;
+            let restored = self.restore_from_file(&decompressed);
+            std::fs::remove_file(&decompressed).ok();
+            return restored;
+        }
+        let dump_conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Self::check_integrity(&dump_conn)
+            .and_then(|()| migrations::pending(&dump_conn).map(drop))
+            .context(format!("Cannot restore the dump: {path:?}"))?;
+
+        let backup_path = self.backup_path("before-restore");
+        self.dump_to_file(&backup_path)
+            .context("Cannot back up the database before the restore")?;
+        {
+            let mut conn = self.writer();
+            let backup = rusqlite::backup::Backup::new(&dump_conn, &mut conn)?;
+            backup.run_to_completion(5, Duration::from_millis(250), None)?;
+        }
+        // NOTE: older dumps are migrated, the current database is backed up already
+        self.migrate(false)?;
+
+        info!(
+            "Database restored from: {path:?}, the previous one backed up to: {backup_path:?}"
+        );
+        Ok(backup_path)
+    }
+
+
+    /// Fails unless PRAGMA integrity_check finds no problems
+    pub fn check_integrity(conn: &Connection) -> Result<()> {
+        let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+        let problems = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        match problems.as_slice() {
+            [ok] if ok == "ok" => Ok(()),
+            _ => anyhow::bail!("Integrity check failed: {}", problems.join("; ")),
+        }
+    }
 }
@@ -0,0 +1,288 @@
+use crate::{config::DumpSettings, database::Database, migrations, *};
+use anyhow::{Context, Result};
+use chrono::{Datelike, Local, NaiveDateTime};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Read},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d-%H-%M-%S";
+
+/// Day, week or month of the dump creation time
+type PeriodOf = fn(&NaiveDateTime) -> (i32, u32);
+
+
+/// Database dump of the dumps directory: database.<timestamp>.db[.gz]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dump {
+    pub path: PathBuf,
+    pub created: NaiveDateTime, // local time
+    pub size: u64,              // bytes on the disk
+}
+
+
+/// Content of the dump listed before the restore
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DumpInfo {
+    pub path: PathBuf,
+    pub created: String, // local time
+    pub size: u64,       // bytes on the disk
+    pub version: u32,    // of the schema
+    pub history: usize,  // entries
+    pub queue: usize,    // queued files
+}
+
+
+/// Timestamped path of a new dump in the dumps directory
+pub fn file_path(dumps_dir: &Path, compress: bool) -> PathBuf {
+    let timestamp = Local::now().format(TIMESTAMP_FORMAT);
+    let extension = if compress { "db.gz" } else { "db" };
+    dumps_dir.join(format!("database.{timestamp}.{extension}"))
+}
+
+
+pub fn is_compressed(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "gz")
+}
+
+
+/// Dumps of the directory, the newest first. Other files are left out.
+pub fn list(dumps_dir: &Path) -> Result<Vec<Dump>> {
+    if !dumps_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut dumps = Vec::new();
+    for entry in fs::read_dir(dumps_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if let Some(created) = parse_name(&name.to_string_lossy()) {
+            dumps.push(Dump {
+                path: entry.path(),
+                created,
+                size: entry.metadata()?.len(),
+            });
+        }
+    }
+    dumps.sort_by(|a, b| b.created.cmp(&a.created).then(b.path.cmp(&a.path)));
+    Ok(dumps)
+}
+
+
+fn parse_name(name: &str) -> Option<NaiveDateTime> {
+    let timestamp = name.strip_prefix("database.")?;
+    let timestamp = timestamp
+        .strip_suffix(".db.gz")
+        .or_else(|| timestamp.strip_suffix(".db"))?;
+    NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()
+}
+
+
+/// Dumps of the directory with their content, the newest first
+pub fn inspect_all(dumps_dir: &Path) -> Result<Vec<DumpInfo>> {
+    list(dumps_dir)?.iter().map(inspect).collect()
+}
+
+
+pub fn inspect(dump: &Dump) -> Result<DumpInfo> {
+    let decompressed = is_compressed(&dump.path)
+        .then(|| decompress(&dump.path))
+        .transpose()?;
+    let counted = count_rows(decompressed.as_deref().unwrap_or(&dump.path));
+    if let Some(decompressed) = decompressed {
+        fs::remove_file(decompressed).ok();
+    }
+    let (version, history, queue) =
+        counted.context(format!("Cannot read the dump: {:?}", dump.path))?;
+    Ok(DumpInfo {
+        path: dump.path.clone(),
+        created: dump.created.format("%Y-%m-%d %H:%M:%S").to_string(),
+        size: dump.size,
+        version,
+        history,
+        queue,
+    })
+}
+
+
+fn count_rows(path: &Path) -> Result<(u32, usize, usize)> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let count = |table: &str| -> Result<usize> {
+        Ok(
+            conn.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                row.get(0)
+            })?,
+        )
+    };
+    Ok((
+        migrations::user_version(&conn)?,
+        count("history")?,
+        count("queue")?,
+    ))
+}
+
+
+/// Path of the dump given as a path, a file name in the dumps directory or "latest"
+pub fn resolve(dumps_dir: &Path, dump: &Path) -> Result<PathBuf> {
+    if dump == Path::new("latest") {
+        return list(dumps_dir)?
+            .into_iter()
+            .next()
+            .map(|dump| dump.path)
+            .context(format!("No database dumps in: {dumps_dir:?}"));
+    }
+    if dump.is_file() {
+        return Ok(dump.to_path_buf());
+    }
+    let in_dumps_dir = dumps_dir.join(dump);
+    if dump.components().count() == 1 && in_dumps_dir.is_file() {
+        return Ok(in_dumps_dir);
+    }
+    anyhow::bail!("No database dump: {dump:?}")
+}
+
+
+/// Dumps the database to the dumps directory, then removes the dumps out of the retention.
+/// None when the dump is skipped, because the database didn't change since the latest one.
+pub fn create(
+    database: &Database,
+    dumps_dir: &Path,
+    settings: &DumpSettings,
+    skip_unchanged: bool,
+) -> Result<Option<PathBuf>> {
+    fs::create_dir_all(dumps_dir)?;
+    let path = file_path(dumps_dir, false);
+    database.dump_to_file(&path)?;
+
+    if skip_unchanged
+        && let Some(latest) = list(dumps_dir)?.into_iter().find(|dump| dump.path != path)
+        && content_sha256(&latest.path).ok() == Some(content_sha256(&path)?)
+    {
+        fs::remove_file(&path)?;
+        info!(
+            "Database unchanged since the dump: {:?}, skipped",
+            latest.path
+        );
+        return Ok(None);
+    }
+
+    let path = if settings.compress {
+        compress(&path)?
+    } else {
+        path
+    };
+    prune(dumps_dir, settings)?;
+    Ok(Some(path))
+}
+
+
+/// Removes the dumps out of the retention, returns the amount removed
+pub fn prune(dumps_dir: &Path, settings: &DumpSettings) -> Result<usize> {
+    let dumps = list(dumps_dir)?;
+    let retained = retained(&dumps, settings);
+    let mut removed = 0;
+    for dump in dumps.iter().filter(|dump| !retained.contains(&dump.path)) {
+        fs::remove_file(&dump.path)
+            .context(format!("Cannot remove the dump: {:?}", dump.path))?;
+        debug!("Removed the dump: {:?}", dump.path);
+        removed += 1;
+    }
+    if removed > 0 {
+        info!("Removed {removed} database dumps out of the retention");
+    }
+    Ok(removed)
+}
+
+
+/// Paths of the dumps (sorted the newest first) kept by the retention: the keep_latest ones
+/// and the newest dump of each of the keep_daily days, keep_weekly weeks and keep_monthly months
+pub fn retained(dumps: &[Dump], settings: &DumpSettings) -> HashSet<PathBuf> {
+    let mut retained: HashSet<PathBuf> = dumps
+        .iter()
+        .take(settings.keep_latest.max(1))
+        .map(|dump| dump.path.clone())
+        .collect();
+
+    let periods: [(usize, PeriodOf); 3] = [
+        (settings.keep_daily, |created| {
+            (created.year(), created.ordinal())
+        }),
+        (settings.keep_weekly, |created| {
+            (created.iso_week().year(), created.iso_week().week())
+        }),
+        (settings.keep_monthly, |created| {
+            (created.year(), created.month())
+        }),
+    ];
+    for (keep, period_of) in periods {
+        let mut periods = Vec::new();
+        for dump in dumps {
+            let period = period_of(&dump.created);
+            if periods.len() < keep && !periods.contains(&period) {
+                periods.push(period);
+                retained.insert(dump.path.clone());
+            }
+        }
+    }
+    retained
+}
+
+
+/// Amount and total size of the dumps
+pub fn disk_usage(dumps_dir: &Path) -> Result<(usize, u64)> {
+    let dumps = list(dumps_dir)?;
+    Ok((dumps.len(), dumps.iter().map(|dump| dump.size).sum()))
+}
+
+
+/// Gzips the dump next to it as .gz and removes the original
+fn compress(path: &Path) -> Result<PathBuf> {
+    let mut compressed_path = path.as_os_str().to_owned();
+    compressed_path.push(".gz");
+    let compressed_path = PathBuf::from(compressed_path);
+
+    let mut encoder = GzEncoder::new(File::create(&compressed_path)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)?;
+    Ok(compressed_path)
+}
+
+
+/// Uncompressed copy of the gzipped dump in the temporary directory, readable only by the user
+/// and removed by the caller. A dump failing to decompress leaves no copy behind.
+pub fn decompress(path: &Path) -> Result<PathBuf> {
+    let target = std::env::temp_dir().join(format!("small-dump-{}.db", uuid::Uuid::new_v4()));
+    let mut decoder = GzDecoder::new(File::open(path)?);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&target)?;
+    if let Err(e) = io::copy(&mut decoder, &mut file) {
+        fs::remove_file(&target).ok();
+        return Err(e).context(format!("Cannot decompress the dump: {path:?}"));
+    }
+    Ok(target)
+}
+
+
+/// SHA-256 of the uncompressed content of the dump
+fn content_sha256(path: &Path) -> Result<Vec<u8>> {
+    let file = File::open(path)?;
+    let mut reader: Box<dyn Read> = if is_compressed(path) {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
@@ -0,0 +1,376 @@
+use crate::{
+    database::{self, Database, History, HistoryFilter, HistoryKey, QueueItem},
+    *,
+};
+use anyhow::{Context, Result};
+use chrono::Local;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, Write},
+    path::Path,
+    str::FromStr,
+};
+
+
+/// Columns of the CSV export
+const CSV_HEADER: &str = "date,timestamp,uuid,file,content,config,size,mime,sha256,width,height,upload_ms,throughput,remote_file,backend,tags,note";
+
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    JsonLines,
+    Csv,
+    Html,
+}
+
+
+impl Format {
+    /// Format of the file extension: .jsonl, .json, .csv, .html or .htm
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+}
+
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format.to_lowercase().as_str() {
+            "jsonl" | "json" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            "html" | "htm" => Ok(Format::Html),
+            _ => {
+                anyhow::bail!("Unknown export format: {format}, expected: jsonl, csv or html")
+            }
+        }
+    }
+}
+
+
+/// Line of the JSON lines export, e.g. {"kind":"history","uuid":..}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Record {
+    History(Box<History>),
+    Queue(QueueItem),
+}
+
+
+/// Amounts of the imported records
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ImportReport {
+    pub added: usize,     // new history entries
+    pub merged: usize,    // existing entries given the tags or note
+    pub unchanged: usize, // existing entries with nothing new
+    pub queued: usize,    // files added to the queue
+}
+
+
+/// Writes the history entries matching the filter (and the queue) in the format,
+/// returns the amount of the history entries written
+pub fn export<W: Write>(
+    database: &Database,
+    filter: &HistoryFilter,
+    format: Format,
+    queue: bool,
+    writer: &mut W,
+) -> Result<usize> {
+    if queue && format != Format::JsonLines {
+        anyhow::bail!("The queue is exported only to JSON lines");
+    }
+    let entries = database.search_history(filter)?;
+    match format {
+        Format::JsonLines => {
+            let queue = if queue {
+                database.get_queue()?
+            } else {
+                Vec::new()
+            };
+            write_json_lines(&entries, &queue, writer)?
+        }
+        Format::Csv => write_csv(&entries, writer)?,
+        Format::Html => write_html(&entries, writer)?,
+    }
+    writer.flush()?;
+    Ok(entries.len())
+}
+
+
+pub fn write_json_lines<W: Write>(
+    entries: &[History],
+    queue: &[QueueItem],
+    writer: &mut W,
+) -> Result<()> {
+    let records = entries
+        .iter()
+        .cloned()
+        .map(|entry| Record::History(Box::new(entry)))
+        .chain(queue.iter().cloned().map(Record::Queue));
+    for record in records {
+        serde_json::to_writer(&mut *writer, &record)?;
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+
+pub fn write_csv<W: Write>(entries: &[History], writer: &mut W) -> Result<()> {
+    writeln!(writer, "{CSV_HEADER}")?;
+    for entry in entries {
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        let fields = [
+            entry.local_time(),
+            entry.timestamp.to_string(),
+            entry.uuid.clone(),
+            entry.file.clone(),
+            entry.content.clone(),
+            optional(entry.config.clone()),
+            optional(entry.size.map(|size| size.to_string())),
+            optional(entry.mime.clone()),
+            optional(entry.sha256.clone()),
+            optional(entry.width.map(|width| width.to_string())),
+            optional(entry.height.map(|height| height.to_string())),
+            optional(entry.upload_ms.map(|upload_ms| upload_ms.to_string())),
+            optional(
+                entry
+                    .throughput
+                    .map(|throughput| format!("{throughput:.0}")),
+            ),
+            optional(entry.remote_file.clone()),
+            optional(entry.backend.clone()),
+            entry.tags.join(" "),
+            optional(entry.note.clone()),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        writeln!(writer, "{}", fields.join(","))?;
+    }
+    Ok(())
+}
+
+
+/// Quotes the field when it has a comma, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+
+/// Self-contained gallery page, with no scripts or stylesheets from elsewhere.
+/// The images are shown from their links, the other files by the IMG_NO_MEDIA.
+pub fn write_html<W: Write>(entries: &[History], writer: &mut W) -> Result<()> {
+    let figures: Vec<String> = entries.iter().map(html_figure).collect();
+    write!(
+        writer,
+        r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>Small history</title>
+  <style>
+    body {{ font-family: sans-serif; background-color: #e1e1e1; margin: 2em; }}
+    h1 {{ text-align: center; font-size: 1.6em; }}
+    .gallery {{ display: grid; grid-template-columns: repeat(auto-fill, minmax(18em, 1fr)); gap: 1.5em; }}
+    figure {{ margin: 0; padding: 0.8em; background-color: #f5f5f5; text-align: center; overflow-wrap: anywhere; }}
+    figure img {{ max-width: 100%; max-height: 14em; background-color: grey; }}
+    .file {{ display: block; height: 14em; background: url("{IMG_NO_MEDIA}") center / contain no-repeat; }}
+    figcaption span {{ display: block; margin-top: 0.3em; }}
+    .date, .details {{ font-size: 0.8em; color: #555; }}
+    footer {{ margin-top: 3em; text-align: center; font-size: 0.8em; color: #555; }}
+  </style>
+</head>
+<body>
+<h1>Small history of: {}</h1>
+<div class="gallery">
+{}
+</div>
+<footer>Exported by Small {} at {}</footer>
+</body>
+</html>
+"#,
+        entries.len(),
+        figures.join("\n"),
+        env!("CARGO_PKG_VERSION"),
+        Local::now().format("%Y-%m-%d %H:%M:%S")
+    )?;
+    Ok(())
+}
+
+
+fn html_figure(entry: &History) -> String {
+    let link = entry
+        .content
+        .split_whitespace()
+        .find(|link| is_web_link(link))
+        .map(html_escape)
+        .unwrap_or_default();
+    let file = html_escape(&entry.file);
+    let is_image = match &entry.mime {
+        Some(mime) => mime.starts_with("image/"),
+        None => {
+            [".png", ".jpg", ".jpeg", ".gif"]
+                .contains(&file_extension(&entry.file).to_lowercase().as_str())
+        }
+    };
+    let preview = if is_image {
+        format!(r#"<img src="{link}" alt="{file}" loading="lazy">"#)
+    } else {
+        r#"<span class="file"></span>"#.to_string()
+    };
+    let mut caption = vec![
+        format!(r#"<span><a href="{link}">{file}</a></span>"#),
+        format!(r#"<span class="date">{}</span>"#, entry.local_time()),
+    ];
+    let details = entry.details();
+    if !details.is_empty() {
+        caption.push(format!(
+            r#"<span class="details">{}</span>"#,
+            html_escape(&details)
+        ));
+    }
+    if !entry.tags.is_empty() {
+        let tags: Vec<String> = entry.tags.iter().map(|tag| format!("#{tag}")).collect();
+        caption.push(format!(
+            r#"<span class="tags">{}</span>"#,
+            html_escape(&tags.join(" "))
+        ));
+    }
+    if let Some(note) = &entry.note {
+        caption.push(format!(
+            r#"<span class="note">{}</span>"#,
+            html_escape(note)
+        ));
+    }
+    format!(
+        r#"<figure id="{}"><a href="{link}">{preview}</a><figcaption>{}</figcaption></figure>"#,
+        html_escape(&entry.uuid),
+        caption.join("")
+    )
+}
+
+
+/// Records of the JSON lines export, or of the JSON array printed by: small history --json
+pub fn read_records<R: BufRead>(reader: R) -> Result<Vec<Record>> {
+    let mut lines = reader.lines().enumerate();
+    let mut records = Vec::new();
+    while let Some((number, line)) = lines.next() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.trim_start().starts_with('[') {
+            let rest: Vec<String> = lines
+                .by_ref()
+                .map(|(_, line)| line)
+                .collect::<Result<_, _>>()?;
+            let array = format!("{line}\n{}", rest.join("\n"));
+            let entries: Vec<History> = serde_json::from_str(&array)
+                .context("Invalid JSON array of history entries")?;
+            records.extend(
+                entries
+                    .into_iter()
+                    .map(|entry| Record::History(Box::new(entry))),
+            );
+            break;
+        }
+        let record = serde_json::from_str(&line)
+            .context(format!("Invalid record in the line: {}", number + 1))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+
+/// Adds the records to the database, all of them or none. A history entry already there
+/// (by its uuid, link or content hash) is merged: it gets the new tags and the note it lacks.
+/// Queue items of files missing on this machine are skipped.
+pub fn import(database: &Database, records: Vec<Record>) -> Result<ImportReport> {
+    let report = database.transaction(|conn| {
+        let mut report = ImportReport::default();
+        for record in records {
+            match record {
+                Record::History(entry) => {
+                    uuid::Uuid::parse_str(&entry.uuid).context(format!(
+                        "Invalid uuid of the history entry: {:?}",
+                        entry.uuid
+                    ))?;
+                    let mut existing =
+                        Database::query_history(conn, HistoryKey::Uuid, &entry.uuid)?;
+                    if existing.is_none() {
+                        existing = Database::query_history(
+                            conn,
+                            HistoryKey::Content,
+                            &entry.content,
+                        )?;
+                    }
+                    if existing.is_none()
+                        && let Some(sha256) = entry.sha256.as_deref()
+                    {
+                        existing = Database::query_history(conn, HistoryKey::Sha256, sha256)?;
+                    }
+                    match existing {
+                        Some(existing) => {
+                            if merge(conn, &existing, &entry)? {
+                                report.merged += 1;
+                            } else {
+                                report.unchanged += 1;
+                            }
+                        }
+                        None => {
+                            Database::insert_history(
+                                conn,
+                                &History {
+                                    tags: database::normalize_tags(&entry.tags),
+                                    ..*entry
+                                },
+                            )?;
+                            report.added += 1;
+                        }
+                    }
+                }
+                Record::Queue(item) => {
+                    uuid::Uuid::parse_str(&item.uuid).context(format!(
+                        "Invalid uuid of the queued file: {:?}",
+                        item.uuid
+                    ))?;
+                    if !Path::new(&item.local_file).exists() {
+                        warn!("Skipped queued file missing here: {}", item.local_file);
+                        continue;
+                    }
+                    report.queued += Database::insert_queue_item(conn, &item)?;
+                }
+            }
+        }
+        Ok(report)
+    })?;
+    info!(
+        "Imported history: {} added, {} merged, {} unchanged, {} queued",
+        report.added, report.merged, report.unchanged, report.queued
+    );
+    Ok(report)
+}
+
+
+/// Gives the existing entry the new tags and the note it lacks, true when changed
+fn merge(conn: &Connection, existing: &History, imported: &History) -> Result<bool> {
+    let tags = database::normalize_tags(&[&existing.tags[..], &imported.tags[..]].concat());
+    let mut changed = false;
+    if tags != existing.tags {
+        changed |= Database::update_tags(conn, &existing.uuid, &tags)?;
+    }
+    if existing.note.is_none()
+        && let Some(note) = imported
+            .note
+            .as_deref()
+            .filter(|note| !note.trim().is_empty())
+    {
+        changed |= Database::update_note(conn, &existing.uuid, Some(note))?;
+    }
+    Ok(changed)
+}
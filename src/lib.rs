@@ -21,27 +21,52 @@
 // Public modules:
 //
 
+/// Command line interface
+pub mod cli;
+/// Clipboard images capture
+pub mod clipboard;
 /// Configuration part of the app
 pub mod config;
+/// Control socket of the running daemon
+pub mod control;
+/// Daemon running all the subsystems
+pub mod daemon;
 
 /// Sqlite db API
 pub mod database;
+/// Database dumps and their retention
+pub mod dumps;
+/// History export and import
+pub mod export;
+/// Versioned database schema
+pub mod migrations;
 /// MacOS Notifications
 pub mod notification;
+/// Configuration hot reload
+pub mod reload;
+/// Config activity schedules
+pub mod schedule;
+/// Secret config values
+pub mod secret;
 /// SFTP sync operations
 pub mod sftp;
+/// Restarts of the failed subsystems
+pub mod supervisor;
 /// Utilities
 pub mod utils;
+/// Configuration validation
+pub mod validation;
 /// Screenshot file watcher
 pub mod watcher;
 /// The local Web API
 pub mod webapi;
 
-/// Make the TEMP_PATTERN a lazy static
+/// Make the TEMP_PATTERN and ADDRESS_PATTERN lazy statics
 use lazy_static::lazy_static;
 use regex::Regex;
 lazy_static! {
     static ref TEMP_PATTERN: Regex = Regex::new(r".*-[a-zA-Z0-9]{4,}$").unwrap();
+    static ref ADDRESS_PATTERN: Regex = Regex::new(r"^https?://[^/\s]+/(\S*/)?$").unwrap();
 }
 
 pub use tracing::{debug, error, info, instrument, trace, warn};
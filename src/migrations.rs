@@ -0,0 +1,326 @@
+use crate::*;
+use anyhow::{Context, Result};
+use rusqlite::{Connection, params};
+
+
+/// Step of the database schema, applied once and recorded in the PRAGMA user_version.
+/// NOTE: the steps up to 4 were done by CREATE TABLE IF NOT EXISTS and added columns
+/// before the schema was versioned, so they must work on the databases already having them.
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub apply: fn(&Connection) -> Result<()>,
+}
+
+
+/// All migrations, ordered by the version. Append new ones, never change the applied ones.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "history and queue tables",
+        apply: history_and_queue_tables,
+    },
+    Migration {
+        version: 2,
+        description: "destination configs of the history and queue",
+        apply: destination_configs,
+    },
+    Migration {
+        version: 3,
+        description: "settings table",
+        apply: settings_table,
+    },
+    Migration {
+        version: 4,
+        description: "upload checkpoints of the queue",
+        apply: upload_checkpoints,
+    },
+    Migration {
+        version: 5,
+        description: "upload metadata of the history",
+        apply: upload_metadata,
+    },
+    Migration {
+        version: 6,
+        description: "full text search of the history",
+        apply: history_search,
+    },
+    Migration {
+        version: 7,
+        description: "tags and notes of the history",
+        apply: tags_and_notes,
+    },
+    Migration {
+        version: 8,
+        description: "indexed links, content hashes and pages of the history",
+        apply: history_indexes,
+    },
+];
+
+
+/// Version of the schema written by this Small version
+pub fn latest_version() -> u32 {
+    MIGRATIONS
+        .last()
+        .map(|migration| migration.version)
+        .unwrap_or_default()
+}
+
+
+pub fn user_version(conn: &Connection) -> Result<u32> {
+    Ok(conn.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+
+/// Migrations not applied to the database yet.
+/// Fails for the database created by a newer Small version.
+pub fn pending(conn: &Connection) -> Result<Vec<&'static Migration>> {
+    let version = user_version(conn)?;
+    let latest = latest_version();
+    if version > latest {
+        anyhow::bail!(
+            "Database schema version: {version} is newer than the supported: {latest}. It was created by a newer Small version, please upgrade."
+        );
+    }
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > version)
+        .collect())
+}
+
+
+/// Whether the database has no tables yet, so there's nothing to back up
+pub fn is_empty(conn: &Connection) -> Result<bool> {
+    let tables: usize = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(tables == 0)
+}
+
+
+/// Applies the migrations in order, each one in its own transaction with its version
+pub fn apply(conn: &mut Connection, migrations: &[&Migration]) -> Result<()> {
+    for migration in migrations {
+        let tx = conn.transaction()?;
+        (migration.apply)(&tx).context(format!(
+            "Database migration: {} ({}) failed",
+            migration.version, migration.description
+        ))?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+        info!(
+            "Migrated database to version: {} ({})",
+            migration.version, migration.description
+        );
+    }
+    Ok(())
+}
+
+
+fn history_and_queue_tables(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            content TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            file TEXT NOT NULL,
+            uuid TEXT NOT NULL UNIQUE
+        );
+        CREATE INDEX IF NOT EXISTS idx_history_timestamp ON history(timestamp);
+        CREATE TABLE IF NOT EXISTS queue (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            local_file TEXT NOT NULL,
+            remote_file TEXT NOT NULL,
+            uuid TEXT NOT NULL UNIQUE
+        );",
+    )?;
+    Ok(())
+}
+
+
+fn destination_configs(conn: &Connection) -> Result<()> {
+    ensure_column(conn, "history", "config", "TEXT")?;
+    ensure_column(conn, "queue", "config", "TEXT")
+}
+
+
+fn settings_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+
+fn upload_checkpoints(conn: &Connection) -> Result<()> {
+    ensure_column(conn, "queue", "upload_size", "INTEGER")?;
+    ensure_column(conn, "queue", "upload_mtime", "INTEGER")
+}
+
+
+fn upload_metadata(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE history ADD COLUMN size INTEGER;
+        ALTER TABLE history ADD COLUMN mime TEXT;
+        ALTER TABLE history ADD COLUMN sha256 TEXT;
+        ALTER TABLE history ADD COLUMN width INTEGER;
+        ALTER TABLE history ADD COLUMN height INTEGER;
+        ALTER TABLE history ADD COLUMN upload_ms INTEGER;
+        ALTER TABLE history ADD COLUMN throughput REAL;
+        ALTER TABLE history ADD COLUMN remote_file TEXT;
+        ALTER TABLE history ADD COLUMN backend TEXT;",
+    )?;
+    Ok(())
+}
+
+
+/// FTS5 index of the history kept up to date by the triggers
+fn history_search(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE VIRTUAL TABLE history_fts USING fts5(
+            file, content, config,
+            content = 'history', content_rowid = 'id'
+        );
+        CREATE TRIGGER history_fts_insert AFTER INSERT ON history BEGIN
+            INSERT INTO history_fts (rowid, file, content, config)
+                VALUES (new.id, new.file, new.content, new.config);
+        END;
+        CREATE TRIGGER history_fts_delete AFTER DELETE ON history BEGIN
+            INSERT INTO history_fts (history_fts, rowid, file, content, config)
+                VALUES ('delete', old.id, old.file, old.content, old.config);
+        END;
+        CREATE TRIGGER history_fts_update AFTER UPDATE ON history BEGIN
+            INSERT INTO history_fts (history_fts, rowid, file, content, config)
+                VALUES ('delete', old.id, old.file, old.content, old.config);
+            INSERT INTO history_fts (rowid, file, content, config)
+                VALUES (new.id, new.file, new.content, new.config);
+        END;
+        INSERT INTO history_fts (history_fts) VALUES ('rebuild');",
+    )?;
+    Ok(())
+}
+
+
+/// The tags and notes are searched too, so the FTS5 index is made again with them
+fn tags_and_notes(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE history ADD COLUMN tags TEXT;
+        ALTER TABLE history ADD COLUMN note TEXT;
+        DROP TRIGGER history_fts_insert;
+        DROP TRIGGER history_fts_delete;
+        DROP TRIGGER history_fts_update;
+        DROP TABLE history_fts;
+        CREATE VIRTUAL TABLE history_fts USING fts5(
+            file, content, config, tags, note,
+            content = 'history', content_rowid = 'id'
+        );
+        CREATE TRIGGER history_fts_insert AFTER INSERT ON history BEGIN
+            INSERT INTO history_fts (rowid, file, content, config, tags, note)
+                VALUES (new.id, new.file, new.content, new.config, new.tags, new.note);
+        END;
+        CREATE TRIGGER history_fts_delete AFTER DELETE ON history BEGIN
+            INSERT INTO history_fts (history_fts, rowid, file, content, config, tags, note)
+                VALUES ('delete', old.id, old.file, old.content, old.config, old.tags, old.note);
+        END;
+        CREATE TRIGGER history_fts_update AFTER UPDATE ON history BEGIN
+            INSERT INTO history_fts (history_fts, rowid, file, content, config, tags, note)
+                VALUES ('delete', old.id, old.file, old.content, old.config, old.tags, old.note);
+            INSERT INTO history_fts (rowid, file, content, config, tags, note)
+                VALUES (new.id, new.file, new.content, new.config, new.tags, new.note);
+        END;
+        INSERT INTO history_fts (history_fts) VALUES ('rebuild');",
+    )?;
+    Ok(())
+}
+
+
+/// The links are unique from now on, so the duplicates the older versions could record
+/// are merged into the first upload: it gets their tags and notes, then they're removed.
+/// The pages are ordered by the timestamp and uuid.
+fn history_indexes(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT id, content, tags, note FROM history
+            WHERE content IN (SELECT content FROM history GROUP BY content HAVING COUNT(*) > 1)
+            ORDER BY content, id",
+    )?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<String>>(3)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut removed = 0;
+    for group in rows.chunk_by(|(_, content, ..), (_, other, ..)| content == other) {
+        let (kept, ..) = group[0];
+        let tags: Vec<&str> = group
+            .iter()
+            .filter_map(|(_, _, tags, _)| tags.as_deref())
+            .collect();
+        let mut notes: Vec<&str> = Vec::new();
+        for note in group.iter().filter_map(|(.., note)| note.as_deref()) {
+            let note = note.trim();
+            if !note.is_empty() && !notes.contains(&note) {
+                notes.push(note);
+            }
+        }
+        let tags = database::normalize_tags(&tags).join(",");
+        conn.execute(
+            "UPDATE history SET tags = ?2, note = ?3 WHERE id = ?1",
+            params![
+                kept,
+                (!tags.is_empty()).then_some(tags),
+                (!notes.is_empty()).then(|| notes.join("; "))
+            ],
+        )?;
+        removed += conn.execute(
+            "DELETE FROM history WHERE content = ?1 AND id != ?2",
+            params![group[0].1, kept],
+        )?;
+    }
+    if removed > 0 {
+        warn!(
+            "Removed {removed} duplicate history entries of the same links, their tags and notes are merged into the first ones"
+        );
+    }
+    conn.execute_batch(
+        "CREATE UNIQUE INDEX idx_history_content ON history(content);
+        CREATE INDEX idx_history_sha256 ON history(sha256);
+        DROP INDEX IF EXISTS idx_history_timestamp;
+        CREATE INDEX idx_history_timestamp_uuid ON history(timestamp, uuid);",
+    )?;
+    Ok(())
+}
+
+
+/// Adds the column unless the table already has it
+fn ensure_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let exists = conn
+        .prepare(&format!(
+            "SELECT 1 FROM pragma_table_info('{table}') WHERE name = ?1"
+        ))?
+        .exists(params![column])?;
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+            [],
+        )?;
+        info!("Added column: {column} to the table: {table}");
+    }
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use crate::{
+    config::{AppConfig, SharedConfig},
+    notification::notification,
+    *,
+};
+use anyhow::Result;
+use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::mpsc, time};
+
+
+/// Watches the config.toml and swaps the SharedConfig on valid changes
+#[derive(Debug)]
+pub struct ConfigReloader {
+    config: SharedConfig,
+}
+
+
+impl ConfigReloader {
+    pub fn new(config: SharedConfig) -> Self {
+        ConfigReloader {
+            config,
+        }
+    }
+
+
+    pub async fn start(self: Arc<Self>) -> Result<()> {
+        let config_file = self.config.current().config_file.clone();
+        let Some(config_dir) = config_file.parent() else {
+            anyhow::bail!("Config file: {config_file:?} has no parent directory");
+        };
+        let config_name = config_file.file_name().map(|name| name.to_os_string());
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut watcher = RecommendedWatcher::new(
+            move |res: Result<Event, notify::Error>| {
+                if let Ok(event) = res {
+                    let _ = tx.send(event);
+                }
+            },
+            Config::default(),
+        )?;
+
+        // NOTE: editors replace the file instead of writing it, so the parent directory is watched
+        watcher.watch(config_dir, RecursiveMode::NonRecursive)?;
+        info!("Watching config file: {config_file:?} for changes");
+
+        while let Some(event) = rx.recv().await {
+            if event.kind.is_access()
+                || !event.paths.iter().any(|path| {
+                    path.file_name().map(|name| name.to_os_string()) == config_name
+                })
+            {
+                continue;
+            }
+
+            // Let the editor finish writing, then drain the burst of events
+            time::sleep(Duration::from_millis(250)).await;
+            while rx.try_recv().is_ok() {}
+
+            if !config_file.exists() {
+                debug!("Config file: {config_file:?} is gone. Keeping the current config");
+                continue;
+            }
+            let _ = self.reload();
+        }
+
+        Ok(())
+    }
+
+
+    /// Re-reads and validates the config file. The current config is kept on failure.
+    pub fn reload(&self) -> Result<()> {
+        let current = self.config.current();
+        match AppConfig::load(&current.config_file) {
+            Ok(mut reloaded) => {
+                if let Some(name) = &current.forced_config {
+                    if reloaded.find_config(name).is_ok() {
+                        reloaded.forced_config = Some(name.clone());
+                    } else {
+                        warn!("Forced config: {name} is gone, using the schedules again");
+                    }
+                }
+                if reloaded.webapi_port != current.webapi_port {
+                    warn!(
+                        "The WebApi port change: {} -> {} requires a restart",
+                        current.webapi_port, reloaded.webapi_port
+                    );
+                }
+                reloaded.log_runtime_settings();
+                self.config.replace(reloaded);
+                info!("Configuration reloaded from: {:?}", current.config_file);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Invalid configuration, keeping the current one: {e:#}");
+                let _ = notification(
+                    &format!("Config reload failed: {e:#}").replace('"', "'"),
+                    "error",
+                    &current.notifications,
+                    &current.sounds,
+                );
+                Err(e)
+            }
+        }
+    }
+}
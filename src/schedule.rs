@@ -0,0 +1,286 @@
+use anyhow::{Context, Result};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Timelike, Weekday};
+
+
+/// Time range within a day, both ends inclusive. Wraps midnight when start > end.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimeRange {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+
+/// Range of dates, both ends inclusive
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DateRange {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+}
+
+
+/// When a config is active. Parsed once from: active_at, active_on, active_dates and holidays.
+/// Empty parts don't restrict anything, so the default Schedule is always active.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule {
+    pub times: Vec<TimeRange>,
+    pub weekdays: Vec<Weekday>,
+    pub dates: Vec<DateRange>,
+    pub holidays: Vec<DateRange>,
+}
+
+
+impl TimeRange {
+    /// Parses "9:00:00-17:00:00" or "9:00-17:00". The "HH:MM" end covers the whole minute.
+    pub fn parse(range: &str) -> Result<Self> {
+        let Some((start, end)) = range.split_once('-') else {
+            anyhow::bail!(
+                "Wrong format of the time range: {range:?}. Should be: HH:MM[:SS]-HH:MM[:SS]"
+            );
+        };
+        Ok(TimeRange {
+            start: Self::parse_time(start, 0)?,
+            end: Self::parse_time(end, 59)?,
+        })
+    }
+
+
+    fn parse_time(time: &str, seconds_of_short_form: u32) -> Result<NaiveTime> {
+        let time = time.trim();
+        NaiveTime::parse_from_str(time, "%H:%M:%S")
+            .or_else(|_| {
+                NaiveTime::parse_from_str(
+                    &format!("{time}:{seconds_of_short_form:02}"),
+                    "%H:%M:%S",
+                )
+            })
+            .context(format!("Invalid time: {time:?}. Should be: HH:MM[:SS]"))
+    }
+
+
+    pub fn wraps_midnight(&self) -> bool {
+        self.start > self.end
+    }
+}
+
+
+impl DateRange {
+    /// Parses a single date "2025-12-24" or a range "2025-12-24..2026-01-02"
+    pub fn parse(range: &str) -> Result<Self> {
+        let (start, end) = range.split_once("..").unwrap_or((range, range));
+        let date_range = DateRange {
+            start: Self::parse_date(start)?,
+            end: Self::parse_date(end)?,
+        };
+        if date_range.start > date_range.end {
+            anyhow::bail!("Date range: {range:?} ends before it starts");
+        }
+        Ok(date_range)
+    }
+
+
+    fn parse_date(date: &str) -> Result<NaiveDate> {
+        let date = date.trim();
+        NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .context(format!("Invalid date: {date:?}. Should be: YYYY-MM-DD"))
+    }
+
+
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        self.start <= date && date <= self.end
+    }
+}
+
+
+impl Schedule {
+    /// Parses the schedule parts. active_at holds comma separated time ranges,
+    /// e.g. "9:00-12:00, 13:00-17:00" or the overnight "17:00:01-8:59:59".
+    pub fn parse(
+        active_at: &str,
+        active_on: &[Weekday],
+        active_dates: &[String],
+        holidays: &[String],
+    ) -> Result<Self> {
+        Ok(Schedule {
+            times: Self::parse_times(active_at)?,
+            weekdays: active_on.to_vec(),
+            dates: Self::parse_dates(active_dates)?,
+            holidays: Self::parse_dates(holidays)?,
+        })
+    }
+
+
+    pub fn parse_times(active_at: &str) -> Result<Vec<TimeRange>> {
+        active_at
+            .split(',')
+            .filter(|range| !range.trim().is_empty())
+            .map(TimeRange::parse)
+            .collect()
+    }
+
+
+    pub fn parse_dates(dates: &[String]) -> Result<Vec<DateRange>> {
+        dates.iter().map(|range| DateRange::parse(range)).collect()
+    }
+
+
+    /// The overnight part of a range wrapping midnight belongs to the day it started,
+    /// so "22:00-2:00" active on Fri is also active on Sat at 1:00.
+    pub fn is_active(&self, now: NaiveDateTime) -> bool {
+        let (date, time) = (now.date(), now.time());
+        if self.times.is_empty() {
+            return self.is_active_day(date);
+        }
+        self.times.iter().any(|range| {
+            if range.wraps_midnight() {
+                (time >= range.start && self.is_active_day(date))
+                    || (time <= range.end
+                        && date.pred_opt().is_some_and(|day| self.is_active_day(day)))
+            } else {
+                range.start <= time && time <= range.end && self.is_active_day(date)
+            }
+        })
+    }
+
+
+    /// True when both schedules can be active at the same moment of some week.
+    /// Holidays are ignored, date ranges only when both schedules have them.
+    pub fn overlaps(&self, other: &Schedule) -> bool {
+        if !self.dates.is_empty()
+            && !other.dates.is_empty()
+            && !self.dates.iter().any(|range| {
+                other.dates.iter().any(|other_range| {
+                    range.start <= other_range.end && other_range.start <= range.end
+                })
+            })
+        {
+            return false;
+        }
+        let intervals = self.weekly_intervals();
+        other.weekly_intervals().iter().any(|(start, end)| {
+            intervals
+                .iter()
+                .any(|(other_start, other_end)| start <= other_end && other_start <= end)
+        })
+    }
+
+
+    /// Active intervals as inclusive (start, end) seconds since the beginning of the week
+    fn weekly_intervals(&self) -> Vec<(u32, u32)> {
+        const DAY: u32 = 86400;
+        let days = if self.weekdays.is_empty() {
+            (0..7).collect::<Vec<_>>()
+        } else {
+            self.weekdays
+                .iter()
+                .map(|day| day.num_days_from_monday())
+                .collect()
+        };
+        let full_day = [TimeRange {
+            start: NaiveTime::MIN,
+            end: NaiveTime::from_hms_opt(23, 59, 59).unwrap_or(NaiveTime::MIN),
+        }];
+        let times = if self.times.is_empty() {
+            &full_day[..]
+        } else {
+            &self.times[..]
+        };
+
+        let mut intervals = Vec::new();
+        for day in days {
+            let day_start = day * DAY;
+            let next_day_start = ((day + 1) % 7) * DAY;
+            for range in times {
+                let start = range.start.num_seconds_from_midnight();
+                let end = range.end.num_seconds_from_midnight();
+                if range.wraps_midnight() {
+                    intervals.push((day_start + start, day_start + DAY - 1));
+                    intervals.push((next_day_start, next_day_start + end));
+                } else {
+                    intervals.push((day_start + start, day_start + end));
+                }
+            }
+        }
+        intervals
+    }
+
+
+    fn is_active_day(&self, date: NaiveDate) -> bool {
+        (self.weekdays.is_empty() || self.weekdays.contains(&date.weekday()))
+            && (self.dates.is_empty() || self.dates.iter().any(|range| range.contains(date)))
+            && !self.holidays.iter().any(|range| range.contains(date))
+    }
+}
+
+
+/// Parses the end of a pause: a duration from now "90s", "30m", "2h", "1d",
+/// the next occurrence of the time "18:00[:00]" or the date and time "2025-12-24 18:00[:00]"
+pub fn parse_until(until: &str, now: NaiveDateTime) -> Result<NaiveDateTime> {
+    let until = until.trim();
+    if let Some(duration) = parse_duration(until)? {
+        return now
+            .checked_add_signed(duration)
+            .context(format!("Pause end out of range: {until:?}"));
+    }
+    if let Some((date, time)) = until.split_once(' ') {
+        return Ok(DateRange::parse_date(date)?.and_time(TimeRange::parse_time(time, 0)?));
+    }
+    let time = TimeRange::parse_time(until, 0).context(format!(
+        "Invalid pause end: {until:?}. Should be a duration: 30m, a time: HH:MM or a date and time: YYYY-MM-DD HH:MM"
+    ))?;
+    let today = now.date().and_time(time);
+    if today > now {
+        Ok(today)
+    } else {
+        Ok(today + TimeDelta::days(1))
+    }
+}
+
+
+/// Parses a bound of a history date range: a duration ago "30m", "2h", "7d",
+/// the date "2025-09-01" (its start, or its end when end is true) or the date and time "2025-09-01 18:00"
+pub fn parse_date_bound(text: &str, now: NaiveDateTime, end: bool) -> Result<NaiveDateTime> {
+    let text = text.trim();
+    if let Some(duration) = parse_duration(text)? {
+        return now
+            .checked_sub_signed(duration)
+            .context(format!("Date out of range: {text:?}"));
+    }
+    if let Some((date, time)) = text.split_once(' ') {
+        return Ok(DateRange::parse_date(date)?.and_time(TimeRange::parse_time(time, 0)?));
+    }
+    let date = DateRange::parse_date(text).context(format!(
+        "Invalid date: {text:?}. Should be a duration ago: 7d, a date: YYYY-MM-DD or a date and time: YYYY-MM-DD HH:MM"
+    ))?;
+    if end {
+        date.and_hms_opt(23, 59, 59)
+            .context(format!("Invalid date: {text:?}"))
+    } else {
+        Ok(date.and_time(NaiveTime::MIN))
+    }
+}
+
+
+/// Parses the duration "90s", "30m", "2h" or "1d", None when the text isn't one.
+/// Amounts of zero or less and durations out of range are errors.
+fn parse_duration(text: &str) -> Result<Option<TimeDelta>> {
+    let Some(unit) = text.chars().last().filter(|unit| "smhd".contains(*unit)) else {
+        return Ok(None);
+    };
+    let Ok(amount) = text[..text.len() - 1].parse::<i64>() else {
+        return Ok(None);
+    };
+    if amount <= 0 {
+        anyhow::bail!("Invalid duration: {text:?}. Should be more than zero");
+    }
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        _ => 86400,
+    };
+    amount
+        .checked_mul(seconds)
+        .and_then(TimeDelta::try_seconds)
+        .map(Some)
+        .context(format!("Duration out of range: {text:?}"))
+}
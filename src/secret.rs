@@ -0,0 +1,106 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, process::Command};
+
+
+/// Secret config value. Either a literal or a reference resolved when it's needed:
+/// - "env:VARIABLE" - value of the environment variable,
+/// - "file:/path/to/file" - content of the file, refused when it's readable by others,
+/// - "cmd:command args" - output of the command, e.g. "cmd:security find-generic-password -w -s small"
+///
+/// The value is never printed by Debug.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "Secret(<empty>)")
+        } else {
+            write!(f, "Secret(<redacted>)")
+        }
+    }
+}
+
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Secret(value.to_string())
+    }
+}
+
+
+impl Secret {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+
+    /// Checks the reference without resolving it, so commands aren't run
+    pub fn check(&self) -> Result<()> {
+        if let Some(variable) = self.0.strip_prefix("env:") {
+            if variable.trim().is_empty() {
+                anyhow::bail!("env: reference without the variable name");
+            }
+        } else if let Some(path) = self.0.strip_prefix("file:") {
+            Self::check_file(path)?;
+        } else if let Some(command) = self.0.strip_prefix("cmd:")
+            && command.trim().is_empty()
+        {
+            anyhow::bail!("cmd: reference without the command");
+        }
+        Ok(())
+    }
+
+
+    /// The secret value. Errors never contain the value itself.
+    pub fn resolve(&self) -> Result<String> {
+        if let Some(variable) = self.0.strip_prefix("env:") {
+            let variable = variable.trim();
+            std::env::var(variable)
+                .context(format!("Secret environment variable: {variable} isn't set"))
+        } else if let Some(path) = self.0.strip_prefix("file:") {
+            Self::check_file(path)?;
+            let value = fs::read_to_string(path.trim())
+                .context(format!("Cannot read the secret file: {path}"))?;
+            Ok(value.trim_end_matches(['\r', '\n']).to_string())
+        } else if let Some(command) = self.0.strip_prefix("cmd:") {
+            let output = Command::new("/bin/sh")
+                .args(["-c", command])
+                .output()
+                .context(format!("Cannot run the secret command: {command}"))?;
+            if !output.status.success() {
+                anyhow::bail!("Secret command: {command} failed with: {}", output.status);
+            }
+            let value = String::from_utf8(output.stdout)
+                .context(format!("Secret command: {command} returned invalid UTF-8"))?;
+            Ok(value.trim_end_matches(['\r', '\n']).to_string())
+        } else {
+            Ok(self.0.clone())
+        }
+    }
+
+
+    fn check_file(path: &str) -> Result<()> {
+        let path = path.trim();
+        let metadata =
+            fs::metadata(path).context(format!("Secret file: {path} isn't readable"))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = metadata.permissions().mode();
+            if mode & 0o004 != 0 {
+                anyhow::bail!(
+                    "Secret file: {path} is world-readable (mode: {:o}). Use: chmod o-r {path}",
+                    mode & 0o777
+                );
+            }
+        }
+        if !metadata.is_file() {
+            anyhow::bail!("Secret file: {path} isn't a regular file");
+        }
+        Ok(())
+    }
+}
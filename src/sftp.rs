@@ -1,24 +1,107 @@
 use crate::{
-    config::AppConfig,
-    database::{Database, History, QueueItem},
+    config::{AppConfig, Config, SharedConfig},
+    database::{Database, History, PauseState, QueueItem},
     notification::notification,
     utils, *,
 };
 use anyhow::{Context, Result};
-use ssh2::Session;
-use std::{fs::File, io::BufReader, net::TcpStream, path::Path, sync::Arc, time::Duration};
+use ssh2::{OpenFlags, OpenType, Session};
+use std::{
+    fs::File,
+    io::{BufReader, Seek, SeekFrom},
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::{sync::mpsc, time};
+use tokio_util::sync::CancellationToken;
+
+
+/// Name of the upload backend recorded in the history
+pub const BACKEND: &str = "sftp";
+
+
+/// Opens an authenticated SSH session to the host of the given config
+pub fn open_session(config: &Config, timeout: u64) -> Result<Session> {
+    // Connect via SSH
+    let tcp = TcpStream::connect(format!("{}:{}", config.hostname, config.ssh_port))
+        .context("Failed to connect to SSH server")?;
+    tcp.set_read_timeout(Some(Duration::from_millis(timeout)))?;
+    tcp.set_write_timeout(Some(Duration::from_millis(timeout)))?;
+
+    let mut sess = Session::new()?;
+    sess.set_tcp_stream(tcp);
+    sess.handshake()?;
+
+    // Authenticate
+    let ssh_key_pass = config
+        .ssh_key_pass
+        .resolve()
+        .context("Failed to resolve the ssh_key_pass")?;
+    sess.userauth_pubkey_file(
+        &config.username,
+        None,
+        &config.ssh_key_path(),
+        if ssh_key_pass.is_empty() {
+            None
+        } else {
+            Some(&ssh_key_pass)
+        },
+    )?;
+
+    if !sess.authenticated() {
+        anyhow::bail!("SSH authentication failed");
+    }
+
+    debug!("SSH connection established");
+    Ok(sess)
+}
+
+
+/// Removes the file from the remote host of the given config
+pub fn remove_remote_file(config: &Config, timeout: u64, remote_file: &str) -> Result<()> {
+    let sess = open_session(config, timeout)?;
+    let sftp = sess.sftp()?;
+    sftp.unlink(Path::new(remote_file))
+        .context(format!("Failed to remove remote file: {remote_file}"))?;
+    info!("Removed remote file: {remote_file}");
+    Ok(())
+}
+
+
+/// Adds the local file to the upload queue of the named (or the scheduled) config.
+/// Returns the link the file will be available at.
+pub fn enqueue_file(
+    app_config: &AppConfig,
+    database: &Database,
+    local_file: &str,
+    config_name: Option<&str>,
+) -> Result<String> {
+    let path = Path::new(local_file)
+        .canonicalize()
+        .context(format!("File not found: {local_file}"))?;
+    if !path.is_file() {
+        anyhow::bail!("Not a regular file: {local_file}");
+    }
+    let config = app_config.config_for(config_name)?;
+    let queue_item = QueueItem::new(&path.to_string_lossy(), &config);
+    database.add_to_queue(&queue_item)?;
+    debug!("Added file to queue: {path:?} for config: {}", config.name);
+    Ok(config.link(&queue_item.uuid, &queue_item.local_file))
+}
+
 
 #[derive(Debug)]
 pub struct SftpManager {
-    config: Arc<AppConfig>,
+    config: SharedConfig,
     database: Arc<Database>,
 }
 
 
 impl SftpManager {
     pub fn new(
-        config: Arc<AppConfig>,
+        config: SharedConfig,
         database: Arc<Database>,
     ) -> (Self, mpsc::UnboundedReceiver<()>) {
         let (_tx, rx) = mpsc::unbounded_channel();
@@ -32,32 +115,90 @@ impl SftpManager {
     }
 
 
-    pub async fn start(self: Arc<Self>) {
-        let mut interval =
-            time::interval(Duration::from_millis(self.config.fs_check_interval));
+    pub async fn start(self: Arc<Self>, shutdown: CancellationToken) {
+        let mut check_interval = self.config.current().fs_check_interval;
+        let mut interval = time::interval(Duration::from_millis(check_interval));
 
-        info!(
-            "Starting SFTP queue processor with check interval: {}ms",
-            self.config.fs_check_interval
-        );
+        info!("Starting SFTP queue processor with check interval: {check_interval}ms");
 
+        let mut was_paused = false;
         loop {
-            interval.tick().await;
-            if let Err(e) = self.process_queue().await {
+            tokio::select! {
+                _ = interval.tick() => (),
+                _ = shutdown.cancelled() => {
+                    info!("SFTP queue processor stopped");
+                    return;
+                }
+            }
+            let reloaded_interval = self.config.current().fs_check_interval;
+            if reloaded_interval != check_interval {
+                info!("SFTP queue processor check interval changed to: {reloaded_interval}ms");
+                check_interval = reloaded_interval;
+                interval = time::interval(Duration::from_millis(check_interval));
+            }
+            match self.paused().await {
+                Ok(paused) => {
+                    if paused != was_paused {
+                        let waiting = self
+                            .database
+                            .call(|database| database.queue_length())
+                            .await
+                            .unwrap_or_default();
+                        if paused {
+                            info!(
+                                "Uploading paused, files are only queued ({waiting} waiting)"
+                            );
+                        } else {
+                            info!("Uploading resumed ({waiting} waiting)");
+                        }
+                        was_paused = paused;
+                    }
+                    if paused {
+                        continue;
+                    }
+                }
+                Err(e) => error!("Error reading the pause state: {e:?}"),
+            }
+            if let Err(e) = self.process_queue(&shutdown).await {
                 error!("Error processing queue: {e:?}");
             }
         }
     }
 
 
-    async fn process_queue(&self) -> Result<()> {
-        let queue = self.database.get_queue()?;
+    /// True while uploading is paused. Clears the pause when its time is over.
+    async fn paused(&self) -> Result<bool> {
+        self.database
+            .call(|database| {
+                let pause = database.get_pause()?;
+                if !pause.paused {
+                    return Ok(false);
+                }
+                if pause.is_active(chrono::Local::now().timestamp()) {
+                    return Ok(true);
+                }
+                database.set_pause(&PauseState::default())?;
+                Ok(false)
+            })
+            .await
+    }
+
+
+    /// Uploads the queued files. Stops between the files on shutdown.
+    pub async fn process_queue(&self, shutdown: &CancellationToken) -> Result<()> {
+        let queue = self.database.call(|database| database.get_queue()).await?;
         if !queue.is_empty() {
             // Build clipboard content
             self.build_clipboard(&queue)?;
 
             // Process each queue item
             for item in &queue {
+                if shutdown.is_cancelled() {
+                    info!(
+                        "Shutting down, the rest of the queue is uploaded on the next start"
+                    );
+                    break;
+                }
                 if let Err(e) = self.process_element(item).await {
                     error!("Error processing queue element: {e:?}");
                 }
@@ -68,31 +209,23 @@ impl SftpManager {
 
 
     fn build_clipboard(&self, queue: &[QueueItem]) -> Result<()> {
+        let app_config = self.config.current();
         let links: Vec<String> = queue
             .iter()
             .map(|item| {
-                let config = self
-                    .config
-                    .select_config()
-                    .expect("One of configs should always be selected!");
-                format!(
-                    "{}{}{}",
-                    config.address,
-                    item.uuid,
-                    file_extension(&item.local_file)
-                )
+                Self::item_config(&app_config, item).link(&item.uuid, &item.local_file)
             })
             .collect();
 
         let content = links.join(", ");
         put_to_clipboard(&content)?;
 
-        if self.config.notifications.clipboard {
+        if app_config.notifications.clipboard {
             let _ = notification(
                 "Link copied to clipboard",
                 "clipboard",
-                &self.config.notifications,
-                &self.config.sounds,
+                &app_config.notifications,
+                &app_config.sounds,
             );
         }
 
@@ -109,76 +242,101 @@ impl SftpManager {
                 "Local file not found or not a regular file: {}. Skipping.",
                 item.local_file
             );
-            self.database.remove_from_queue(&item.uuid)?;
-            return Ok(());
+            return self.remove_from_queue(item).await;
         }
 
         // Check for temp file pattern
         if TEMP_PATTERN.is_match(&item.local_file) {
             debug!("File matches temp pattern, skipping: {}", item.local_file);
-            self.database.remove_from_queue(&item.uuid)?;
-            return Ok(());
+            return self.remove_from_queue(item).await;
         }
 
         // Upload file
+        let app_config = self.config.current();
+        let config = Self::item_config(&app_config, item);
         let remote_file = format!("{}{}", item.remote_file, file_extension(&item.local_file));
-        self.send_file(&item.local_file, &remote_file).await?;
+        let started = Instant::now();
+        let sent = self
+            .send_file(&app_config, &config, item, &remote_file)
+            .await?;
+
+        let (item, duration) = (item.clone(), started.elapsed());
+        self.database
+            .call(move |database| {
+                Self::record_upload(
+                    database,
+                    &app_config,
+                    &config,
+                    &item,
+                    &remote_file,
+                    sent,
+                    duration,
+                )
+            })
+            .await
+    }
 
-        // Add to history
-        self.add_to_history(item)?;
-
-        // Remove from queue
-        self.database.remove_from_queue(&item.uuid)?;
 
+    async fn remove_from_queue(&self, item: &QueueItem) -> Result<()> {
+        let uuid = item.uuid.clone();
+        self.database
+            .call(move |database| database.remove_from_queue(&uuid))
+            .await?;
         Ok(())
     }
 
 
-    async fn send_file(&self, local_file: &str, remote_file: &str) -> Result<()> {
-        let config = &self
-            .config
-            .select_config()
-            .expect("One of configs should always be selected!");
-
-        // Connect via SSH
-        let tcp = TcpStream::connect(format!("{}:{}", config.hostname, config.ssh_port))
-            .context("Failed to connect to SSH server")?;
-        tcp.set_read_timeout(Some(Duration::from_millis(
-            self.config.ssh_connection_timeout,
-        )))?;
-        tcp.set_write_timeout(Some(Duration::from_millis(
-            self.config.ssh_connection_timeout,
-        )))?;
-
-        let mut sess = Session::new()?;
-        sess.set_tcp_stream(tcp);
-        sess.handshake()?;
-
-        // Authenticate
-        let ssh_private_key = if config.ssh_key.is_empty() {
-            ".ssh/id_ed25519"
-        } else {
-            &config.ssh_key
-        };
+    /// The destination config of the queued item
+    fn item_config(app_config: &AppConfig, item: &QueueItem) -> Config {
+        app_config
+            .config_for(item.config.as_deref())
+            .unwrap_or_else(|e| {
+                warn!(
+                    "{e}. Uploading: {} with the scheduled config",
+                    item.local_file
+                );
+                app_config
+                    .select_config()
+                    .expect("One of configs should always be selected!")
+            })
+    }
 
-        sess.userauth_pubkey_file(
-            &config.username,
-            None,
-            Path::new(&home::home_dir().expect("Home dir has to be set!"))
-                .join(ssh_private_key)
-                .as_path(),
-            if config.ssh_key_pass.is_empty() {
-                None
-            } else {
-                Some(&config.ssh_key_pass)
-            },
-        )?;
 
-        if !sess.authenticated() {
-            anyhow::bail!("SSH authentication failed");
-        }
+    /// Uploads the file on the blocking threads, so the shutdown doesn't wait for it longer
+    /// than the shutdown_timeout. Returns the amount of bytes sent.
+    async fn send_file(
+        &self,
+        app_config: &Arc<AppConfig>,
+        config: &Config,
+        item: &QueueItem,
+        remote_file: &str,
+    ) -> Result<u64> {
+        let (database, app_config, config, item, remote_file) = (
+            self.database.clone(),
+            app_config.clone(),
+            config.clone(),
+            item.clone(),
+            remote_file.to_string(),
+        );
+        tokio::task::spawn_blocking(move || {
+            Self::transfer(&database, &app_config, &config, &item, &remote_file)
+        })
+        .await
+        .context("Upload task failed")?
+    }
+
 
-        debug!("SSH connection established");
+    /// Sends the file over SFTP. An upload interrupted by the shutdown is resumed from the size
+    /// of the remote file, when the local file hasn't changed since the upload started.
+    fn transfer(
+        database: &Database,
+        app_config: &AppConfig,
+        config: &Config,
+        item: &QueueItem,
+        remote_file: &str,
+    ) -> Result<u64> {
+        let local_file = &item.local_file;
+        let sess = open_session(config, app_config.ssh_connection_timeout)?;
 
         // Start SFTP session
         let sftp = sess.sftp()?;
@@ -186,6 +344,7 @@ impl SftpManager {
 
         // Check remote file
         let local_size = local_file_size(local_file)?;
+        let local_mtime = local_file_mtime(local_file)?;
         let remote_size = sftp
             .stat(Path::new(remote_file))
             .map(|stat| stat.size.unwrap_or(0))
@@ -197,58 +356,107 @@ impl SftpManager {
 
         if remote_size > 0 && remote_size == local_size {
             info!("Found file of same size already uploaded. Skipping");
-            return Ok(());
+            return Ok(0);
         }
 
+        let checkpoint = database.upload_checkpoint(&item.uuid)?;
+        database.start_upload(&item.uuid, local_size, local_mtime)?;
+        let resume_from = if remote_size > 0
+            && remote_size < local_size
+            && checkpoint == Some((local_size, local_mtime))
+        {
+            remote_size
+        } else {
+            0
+        };
+
         // Upload file
         let mut local = BufReader::new(File::open(local_file)?);
-        let mut remote = sftp.create(Path::new(remote_file))?;
+        let mut remote = if resume_from > 0 {
+            info!(
+                "Resuming the interrupted upload of: {local_file} from: {:.2}KiB",
+                size_kib(resume_from)
+            );
+            local.seek(SeekFrom::Start(resume_from))?;
+            sftp.open_mode(
+                Path::new(remote_file),
+                OpenFlags::WRITE | OpenFlags::APPEND,
+                0o644,
+                OpenType::File,
+            )?
+        } else {
+            sftp.create(Path::new(remote_file))?
+        };
 
         stream_file_to_remote(
             &mut local,
             &mut remote,
-            self.config.sftp_buffer_size,
-            local_size,
+            app_config.sftp_buffer_size,
+            local_size - resume_from,
         )?;
 
-        if self.config.notifications.upload {
+        if app_config.notifications.upload {
             let _ = notification(
                 "Uploaded successfully.",
                 "upload",
-                &self.config.notifications,
-                &self.config.sounds,
+                &app_config.notifications,
+                &app_config.sounds,
             );
         }
-        Ok(())
+        Ok(local_size - resume_from)
     }
 
 
-    fn add_to_history(&self, queue_item: &QueueItem) -> Result<()> {
-        let config = self
-            .config
-            .select_config()
-            .expect("One of configs should always be selected!");
-        let content = format!(
-            "{}{}{}",
-            config.address,
-            queue_item.uuid,
-            file_extension(&queue_item.local_file)
-        );
+    /// Records the uploaded file in the history, unless its link is there already, and removes
+    /// it from the queue. The file is hashed on the blocking threads too. The file spooled by
+    /// Small (from the clipboard or the stdin) is removed as well, its link is all that's left.
+    pub fn record_upload(
+        database: &Database,
+        app_config: &AppConfig,
+        config: &Config,
+        queue_item: &QueueItem,
+        remote_file: &str,
+        sent: u64,
+        duration: Duration,
+    ) -> Result<()> {
+        let content = config.link(&queue_item.uuid, &queue_item.local_file);
+        let local_file = &queue_item.local_file;
 
         // Check if already in history
-        let history = self.database.get_history(None)?;
-        let exists = history.iter().any(|h| h.content.contains(&content));
-
-        if !exists {
+        if database.find_history_by_content(&content)?.is_none() {
+            let dimensions = image_dimensions(local_file);
             let history_item = History {
                 content,
                 timestamp: chrono::Local::now().timestamp(),
-                file: queue_item.local_file.clone(),
+                file: local_file.clone(),
                 uuid: uuid::Uuid::new_v4().to_string(),
+                config: Some(config.name.clone()),
+                size: local_file_size(local_file).ok(),
+                mime: Some(file_mime(local_file)),
+                sha256: file_sha256(local_file)
+                    .inspect_err(|e| warn!("Cannot hash: {local_file}: {e}"))
+                    .ok(),
+                width: dimensions.map(|(width, _)| width),
+                height: dimensions.map(|(_, height)| height),
+                upload_ms: Some(duration.as_millis() as u64),
+                throughput: (sent > 0 && !duration.is_zero())
+                    .then(|| sent as f64 / duration.as_secs_f64()),
+                remote_file: Some(remote_file.to_string()),
+                backend: Some(String::from(BACKEND)),
+                tags: app_config.tags_for(config, local_file),
+                note: None,
             };
-            self.database.add_history(&history_item)?;
+            database.add_history(&history_item)?;
         }
 
+        // Remove from queue
+        database.remove_from_queue(&queue_item.uuid)?;
+
+        if let Some(spool_dir) = app_config.spool_dir_of(Path::new(local_file)) {
+            remove_spooled(Path::new(local_file), &spool_dir)
+                .inspect_err(|e| warn!("Cannot remove the spooled file: {local_file}: {e}"))
+                .ok();
+        }
         Ok(())
     }
 }
@@ -0,0 +1,138 @@
+use crate::*;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{task::JoinHandle, time};
+use tokio_util::sync::CancellationToken;
+
+
+/// A run longer than this isn't a crash loop, so the backoff starts over
+const STABLE_RUN: Duration = Duration::from_secs(60);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+
+/// State of a supervised subsystem
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubsystemStatus {
+    pub running: bool,
+    pub restarts: u32,              // since the daemon started
+    pub last_error: Option<String>, // why it stopped the last time
+    pub last_restart: Option<i64>,  // timestamp
+    pub gave_up: bool,              // failed more than max_restarts times in a row
+}
+
+
+/// Restarts the subsystems that fail or stop, with exponential backoff.
+/// Gives up on a subsystem failing more than max_restarts times in a row.
+#[derive(Debug, Clone)]
+pub struct Supervisor {
+    statuses: Arc<Mutex<BTreeMap<String, SubsystemStatus>>>,
+    shutdown: CancellationToken,
+    max_restarts: u32,
+}
+
+
+impl Supervisor {
+    pub fn new(shutdown: CancellationToken, max_restarts: u32) -> Self {
+        Supervisor {
+            statuses: Arc::new(Mutex::new(BTreeMap::new())),
+            shutdown,
+            max_restarts,
+        }
+    }
+
+
+    /// Runs the subsystem made by the factory until the shutdown.
+    /// The returned handle ends with an error when the supervisor gives up.
+    pub fn spawn<F, Fut>(&self, name: &str, factory: F) -> JoinHandle<Result<()>>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let name = name.to_string();
+        tokio::spawn(async move { supervisor.supervise(&name, factory).await })
+    }
+
+
+    async fn supervise<F, Fut>(&self, name: &str, factory: F) -> Result<()>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let mut failures = 0;
+        loop {
+            self.update(name, |status| status.running = true);
+            let started = Instant::now();
+
+            // NOTE: the subsystem runs in its own task, so its panic is an error here
+            let error = match tokio::spawn(factory()).await {
+                Ok(Ok(())) => String::from("stopped"),
+                Ok(Err(e)) => format!("{e:#}"),
+                Err(e) => format!("panicked: {e}"),
+            };
+            self.update(name, |status| status.running = false);
+            if self.shutdown.is_cancelled() {
+                return Ok(());
+            }
+
+            if started.elapsed() >= STABLE_RUN {
+                failures = 0;
+            }
+            failures += 1;
+            if failures > self.max_restarts {
+                error!("{name} failed {failures} times in a row, giving up: {error}");
+                self.update(name, |status| {
+                    status.last_error = Some(error.clone());
+                    status.gave_up = true;
+                });
+                anyhow::bail!("{name} failed {failures} times in a row: {error}");
+            }
+
+            let backoff = Self::backoff(failures);
+            warn!(
+                "{name} {error}. Restarting in {}s ({failures}/{})",
+                backoff.as_secs(),
+                self.max_restarts
+            );
+            self.update(name, |status| {
+                status.last_error = Some(error.clone());
+                status.restarts += 1;
+                status.last_restart = Some(chrono::Local::now().timestamp());
+            });
+            tokio::select! {
+                _ = time::sleep(backoff) => (),
+                _ = self.shutdown.cancelled() => return Ok(()),
+            }
+        }
+    }
+
+
+    /// Delay before the restart after the failures in a row: 1s, 2s, 4s… up to 60s
+    pub fn backoff(failures: u32) -> Duration {
+        let seconds = 1u64
+            .checked_shl(failures.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        Duration::from_secs(seconds).min(MAX_BACKOFF)
+    }
+
+
+    /// States of the subsystems by their names
+    pub fn statuses(&self) -> BTreeMap<String, SubsystemStatus> {
+        self.statuses.lock().unwrap().clone()
+    }
+
+
+    fn update<F>(&self, name: &str, change: F)
+    where
+        F: FnOnce(&mut SubsystemStatus),
+    {
+        let mut statuses = self.statuses.lock().unwrap();
+        change(statuses.entry(name.to_string()).or_default());
+    }
+}
@@ -1,10 +1,52 @@
 use super::*;
 use crate::{
-    config::{AppConfig, Config},
-    database::{Database, QueueItem},
+    cli::{Cli, Command, QueueAction},
+    clipboard::ClipboardWatcher,
+    config::{
+        AppConfig, AutoTags, ClipboardSettings, Config, DumpSettings, RuntimeSettings,
+        SharedConfig, WatcherMode,
+    },
+    control::{ControlRequest, ControlServer},
+    daemon::{InstanceLock, Shutdown},
+    database::{self, Database, History, HistoryFilter, PauseState, QueueItem},
+    dumps,
+    export::{self, Format, ImportReport},
+    migrations,
+    reload::ConfigReloader,
+    schedule::{self, Schedule},
+    secret::Secret,
+    sftp::SftpManager,
+    supervisor::Supervisor,
+    validation::{ValidationReport, validate_configs},
+    watcher::FileWatcher,
+    webapi::WebApi,
     *,
 };
-use chrono::{Datelike, Local, NaiveTime, Weekday};
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime, Weekday};
+use clap::Parser;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+
+/// New directory path in the temp directory, created by the test when needed
+fn test_dir() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("small-test-{}", uuid::Uuid::new_v4()))
+}
+
+
+/// Database path in its own test_dir, so the -wal, -shm and backup files go away with it
+fn test_db_path() -> std::path::PathBuf {
+    let dir = test_dir();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join("small.db")
+}
+
+
+fn remove_test_db(db_path: &std::path::Path) {
+    if let Some(dir) = db_path.parent() {
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
 
 
 #[test]
@@ -88,17 +130,23 @@ pub fn select_config_test_active_on() {
         active_at: String::from("00:00:00-23:59:59"),
         active_on: vec![today],
         ..Config::default()
-    };
+    }
+    .with_schedule()
+    .unwrap();
     let config2 = Config {
         active_at: String::from("00:00:00-23:59:59"),
         active_on: vec![tomorrow],
         ..Config::default()
-    };
+    }
+    .with_schedule()
+    .unwrap();
     let default_config = Config {
         active_at: String::from("00:00:00-00:00:01"), // not active now
         default: true,
         ..Config::default()
-    };
+    }
+    .with_schedule()
+    .unwrap();
 
     let app_config = AppConfig {
         configs: vec![config1.clone(), config2, default_config],
@@ -108,3 +156,1838 @@ pub fn select_config_test_active_on() {
     let selected_config = app_config.select_config().unwrap();
     assert_eq!(selected_config, config1);
 }
+
+
+#[test]
+fn schedule_overnight_and_multi_range_test() {
+    let at = |date: &str, time: &str| {
+        NaiveDateTime::parse_from_str(&format!("{date} {time}"), "%Y-%m-%d %H:%M:%S").unwrap()
+    };
+
+    // 2025-06-06 is a Friday
+    let overnight = Schedule::parse("17:00:01-8:59:59", &[Weekday::Fri], &[], &[]).unwrap();
+    assert!(overnight.is_active(at("2025-06-06", "23:30:00")));
+    assert!(overnight.is_active(at("2025-06-07", "08:59:59")));
+    assert!(!overnight.is_active(at("2025-06-07", "09:00:00")));
+    assert!(!overnight.is_active(at("2025-06-06", "08:00:00")));
+
+    let ranges = Schedule::parse("9:00-12:00, 13:00-17:00", &[], &[], &[]).unwrap();
+    assert!(ranges.is_active(at("2025-06-06", "12:00:59")));
+    assert!(!ranges.is_active(at("2025-06-06", "12:30:00")));
+    assert!(ranges.is_active(at("2025-06-06", "13:00:00")));
+
+    let dated = Schedule::parse(
+        "",
+        &[],
+        &[String::from("2025-06-01..2025-06-30")],
+        &[String::from("2025-06-06")],
+    )
+    .unwrap();
+    assert!(dated.is_active(at("2025-06-05", "10:00:00")));
+    assert!(!dated.is_active(at("2025-06-06", "10:00:00")));
+    assert!(!dated.is_active(at("2025-07-01", "10:00:00")));
+
+    assert!(Schedule::parse("9:00:00", &[], &[], &[]).is_err());
+    assert!(Schedule::parse("9:00-25:00", &[], &[], &[]).is_err());
+    assert!(Schedule::parse("", &[], &[String::from("2025-06-30..2025-06-01")], &[]).is_err());
+}
+
+
+#[test]
+fn config_watcher_mode_test() {
+    let base = r#"
+        username = "user"
+        hostname = "host"
+        ssh_key = ""
+        ssh_port = 22
+        address = "https://host/"
+        remote_path = "/tmp"
+        ssh_key_pass = ""
+        watch_path = "/tmp"
+        active_at = ""
+        active_on = []
+        default = true
+    "#;
+    let native: Config = toml::from_str(base).unwrap();
+    assert_eq!(native.watcher, WatcherMode::Native);
+
+    let poll: Config = toml::from_str(&format!("{base}\nwatcher = \"poll\"")).unwrap();
+    assert_eq!(poll.watcher, WatcherMode::Poll);
+}
+
+
+#[tokio::test]
+async fn poll_watcher_queues_new_files_test() {
+    let dir = test_dir();
+    let watch_path = dir.join("mount");
+    std::fs::create_dir_all(&watch_path).unwrap();
+    let app_config = AppConfig {
+        configs: vec![Config {
+            name: String::from("nfs"),
+            watch_path: watch_path.to_string_lossy().to_string(),
+            watcher: WatcherMode::Poll,
+            default: true,
+            ..Config::default()
+        }],
+        fs_check_interval: 50,
+        ..AppConfig::default()
+    };
+    let database = std::sync::Arc::new(Database::new(dir.join("small.db")).unwrap());
+    let watcher = std::sync::Arc::new(FileWatcher::new(
+        SharedConfig::new(app_config),
+        database.clone(),
+    ));
+    let shutdown = CancellationToken::new();
+    let handle = tokio::spawn(watcher.start(shutdown.clone()));
+
+    // NOTE: the files there before the first scan aren't new, the ones written later are
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let file = watch_path.join("report.pdf");
+    std::fs::write(&file, "pdf").unwrap();
+    let mut queue = Vec::new();
+    for _ in 0..100 {
+        queue = database.get_queue().unwrap();
+        if !queue.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue[0].local_file, file.to_string_lossy());
+    assert_eq!(queue[0].config.as_deref(), Some("nfs"));
+
+    shutdown.cancel();
+    handle.await.unwrap().unwrap();
+    std::fs::remove_dir_all(dir).ok();
+}
+
+
+#[test]
+fn queue_move_on_rename_test() {
+    let db_path = test_db_path();
+    let database = Database::new(&db_path).unwrap();
+    let pending = QueueItem {
+        local_file: String::from("/tmp/.Screenshot-a1B2c3"),
+        remote_file: String::from("/remote/old-uuid"),
+        uuid: String::from("old-uuid"),
+        config: None,
+    };
+    database.add_to_queue(&pending).unwrap();
+
+    let renamed = QueueItem {
+        local_file: String::from("/tmp/Screenshot.png"),
+        remote_file: String::from("/remote/new-uuid"),
+        uuid: String::from("new-uuid"),
+        config: Some(String::from("client")),
+    };
+    assert!(
+        database
+            .move_in_queue(&pending.local_file, &renamed)
+            .unwrap()
+    );
+    assert!(
+        !database
+            .move_in_queue("/tmp/never-queued", &renamed)
+            .unwrap()
+    );
+
+    let queue = database.get_queue().unwrap();
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue[0].uuid, "new-uuid");
+    assert_eq!(queue[0].config.as_deref(), Some("client"));
+
+    assert_eq!(
+        database
+            .remove_from_queue_by_file(&renamed.local_file)
+            .unwrap(),
+        1
+    );
+    assert!(database.get_queue().unwrap().is_empty());
+    remove_test_db(&db_path);
+}
+
+
+#[test]
+fn clipboard_capture_through_queue_test() {
+    let dir = test_dir();
+    let watch_path = dir.join("watched");
+    std::fs::create_dir_all(&watch_path).unwrap();
+    let config = Config {
+        name: String::from("client"),
+        remote_path: String::from("/remote"),
+        address: String::from("https://s.example.com/"),
+        watch_path: watch_path.to_string_lossy().to_string(),
+        default: true,
+        ..Config::default()
+    };
+    let app_config = AppConfig {
+        configs: vec![config.clone()],
+        clipboard: ClipboardSettings {
+            capture_images: true,
+            spool_path: dir.join("clipboard").to_string_lossy().to_string(),
+        },
+        ..AppConfig::default()
+    };
+    let database = std::sync::Arc::new(Database::new(dir.join("small.db")).unwrap());
+    let clipboard =
+        ClipboardWatcher::new(SharedConfig::new(app_config.clone()), database.clone());
+    clipboard
+        .process_image(&app_config, 2, 1, &[255; 8])
+        .unwrap();
+
+    let queue = database.get_queue().unwrap();
+    assert_eq!(queue.len(), 1);
+    let item = &queue[0];
+    let spooled = std::path::Path::new(&item.local_file);
+    assert!(spooled.starts_with(app_config.clipboard_spool_dir()));
+    assert!(!TEMP_PATTERN.is_match(&item.local_file));
+    assert_eq!(item.config.as_deref(), Some("client"));
+    assert_eq!(item.remote_file, format!("/remote/{}", item.uuid));
+
+    // NOTE: the spooled capture is removed once its upload is in the history
+    SftpManager::record_upload(
+        &database,
+        &app_config,
+        &config,
+        item,
+        &format!("{}.png", item.remote_file),
+        68,
+        Duration::from_millis(10),
+    )
+    .unwrap();
+    assert!(database.get_queue().unwrap().is_empty());
+    let entry = database
+        .find_history_by_file(&item.local_file)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        entry.content,
+        format!("https://s.example.com/{}.png", item.uuid)
+    );
+    assert_eq!((entry.width, entry.height), (Some(2), Some(1)));
+    assert!(!spooled.exists());
+    assert!(app_config.clipboard_spool_dir().is_dir());
+
+    // NOTE: files of the watch paths are never removed, even when the spool_path is there
+    let shot = watch_path.join("shot.png");
+    std::fs::write(&shot, "png").unwrap();
+    let app_config = AppConfig {
+        clipboard: ClipboardSettings {
+            capture_images: true,
+            spool_path: watch_path.to_string_lossy().to_string(),
+        },
+        ..app_config
+    };
+    assert_eq!(app_config.spool_dir_of(&shot), None);
+    std::fs::remove_dir_all(dir).ok();
+}
+
+
+#[test]
+fn named_and_bound_configs_test() {
+    let scheduled = Config {
+        name: String::from("scheduled"),
+        watch_path: String::from("/Users/me/Desktop"),
+        default: true,
+        ..Config::default()
+    };
+    let client = Config {
+        name: String::from("client"),
+        watch_path: String::from("/Users/me/Exports/"),
+        active_at: String::from("00:00:00-00:00:01"),
+        bind_watch_path: true,
+        ..Config::default()
+    }
+    .with_schedule()
+    .unwrap();
+    let app_config = AppConfig {
+        configs: vec![client, scheduled],
+        ..AppConfig::default()
+    };
+
+    assert_eq!(app_config.find_config("client").unwrap().name, "client");
+    assert!(app_config.find_config("unknown").is_err());
+    assert_eq!(
+        app_config.config_for(Some("client")).unwrap().name,
+        "client"
+    );
+    assert_eq!(
+        app_config
+            .config_for_path("/Users/me/Exports/design.png")
+            .unwrap()
+            .name,
+        "client"
+    );
+}
+
+
+#[test]
+fn database_adds_missing_columns_test() {
+    let db_path = test_db_path();
+    {
+        let conn = rusqlite::Connection::open(&db_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE history (id INTEGER PRIMARY KEY AUTOINCREMENT, content TEXT NOT NULL, timestamp INTEGER NOT NULL, file TEXT NOT NULL, uuid TEXT NOT NULL UNIQUE);
+             INSERT INTO history (content, timestamp, file, uuid) VALUES ('https://s.example.com/a.png', 1, '/tmp/a.png', 'a');",
+        )
+        .unwrap();
+    }
+
+    let database = Database::new(&db_path).unwrap();
+    let history = database.get_history(None).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].config, None);
+    remove_test_db(&db_path);
+}
+
+
+#[test]
+fn database_migrations_test() {
+    let dir = test_dir();
+    std::fs::create_dir_all(&dir).unwrap();
+    let latest = migrations::latest_version();
+
+    // NOTE: a fresh database is created at the latest version without a backup
+    let fresh_path = dir.join("fresh.db");
+    drop(Database::new(&fresh_path).unwrap());
+    let conn = rusqlite::Connection::open(&fresh_path).unwrap();
+    assert_eq!(migrations::user_version(&conn).unwrap(), latest);
+    drop(conn);
+
+    // NOTE: an unversioned database with some of the columns already added
+    let legacy_path = dir.join("legacy.db");
+    {
+        let conn = rusqlite::Connection::open(&legacy_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE history (id INTEGER PRIMARY KEY AUTOINCREMENT, content TEXT NOT NULL, timestamp INTEGER NOT NULL, file TEXT NOT NULL, uuid TEXT NOT NULL UNIQUE, config TEXT);
+             CREATE TABLE queue (id INTEGER PRIMARY KEY AUTOINCREMENT, local_file TEXT NOT NULL, remote_file TEXT NOT NULL, uuid TEXT NOT NULL UNIQUE, config TEXT);
+             INSERT INTO history (content, timestamp, file, uuid, config) VALUES ('https://s.example.com/a.png', 1, '/tmp/a.png', 'a', 'work');
+             INSERT INTO history (content, timestamp, file, uuid, config) VALUES ('https://s.example.com/a.png', 2, '/tmp/a.png', 'a-again', 'work');",
+        )
+        .unwrap();
+    }
+    let database = Database::new(&legacy_path).unwrap();
+    let history = database.get_history(None).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].uuid, "a");
+    assert_eq!(history[0].config.as_deref(), Some("work"));
+    database.start_upload("missing", 1, 1).unwrap();
+    drop(database);
+    let backups: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("legacy.db.v0.")
+        })
+        .collect();
+    assert_eq!(backups.len(), 1);
+    let backup = rusqlite::Connection::open(backups[0].path()).unwrap();
+    assert_eq!(migrations::user_version(&backup).unwrap(), 0);
+
+    // NOTE: reopening applies nothing
+    drop(Database::new(&legacy_path).unwrap());
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+
+    // NOTE: a database of a newer Small version is refused, dumps of it too
+    let newer_path = dir.join("newer.db");
+    {
+        let conn = rusqlite::Connection::open(&newer_path).unwrap();
+        conn.pragma_update(None, "user_version", latest + 1)
+            .unwrap();
+    }
+    let error = Database::new(&newer_path).unwrap_err();
+    assert!(format!("{error:#}").contains("newer Small version"));
+    let database = Database::new(&fresh_path).unwrap();
+    assert!(database.restore_from_file(&newer_path).is_err());
+
+    // NOTE: the duplicate links of the version 7 give their tags and notes to the first upload
+    let duplicates_path = dir.join("duplicates.db");
+    {
+        let mut conn = rusqlite::Connection::open(&duplicates_path).unwrap();
+        let pending = migrations::pending(&conn).unwrap();
+        migrations::apply(&mut conn, &pending[..pending.len() - 1]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO history (content, timestamp, file, uuid, tags, note) VALUES ('https://s.example.com/a.png', 1, '/tmp/a.png', 'a', 'design', NULL);
+             INSERT INTO history (content, timestamp, file, uuid, tags, note) VALUES ('https://s.example.com/a.png', 2, '/tmp/a.png', 'a2', 'client,design', 'logo');
+             INSERT INTO history (content, timestamp, file, uuid, tags, note) VALUES ('https://s.example.com/a.png', 3, '/tmp/a.png', 'a3', NULL, 'v2');
+             INSERT INTO history (content, timestamp, file, uuid, tags, note) VALUES ('https://s.example.com/b.png', 4, '/tmp/b.png', 'b', 'other', 'b note');",
+        )
+        .unwrap();
+    }
+    let database = Database::new(&duplicates_path).unwrap();
+    let history = database.get_history(None).unwrap();
+    assert_eq!(history.len(), 2);
+    let first = database.find_history("a").unwrap().unwrap();
+    assert_eq!(first.tags, ["design", "client"]);
+    assert_eq!(first.note.as_deref(), Some("logo; v2"));
+    let other = database.find_history("b").unwrap().unwrap();
+    assert_eq!(other.tags, ["other"]);
+    assert_eq!(other.note.as_deref(), Some("b note"));
+    std::fs::remove_dir_all(dir).ok();
+}
+
+
+#[test]
+fn config_reload_keeps_current_on_failure_test() {
+    let config_file =
+        std::env::temp_dir().join(format!("small-test-{}.toml", uuid::Uuid::new_v4()));
+    std::fs::write(&config_file, "open_history_on_start = \"not a bool\"").unwrap();
+
+    let shared_config = SharedConfig::new(AppConfig {
+        config_file: config_file.clone(),
+        amount_history_load: 50,
+        ..AppConfig::default()
+    });
+    let updates = shared_config.subscribe();
+    let reloader = ConfigReloader::new(shared_config.clone());
+
+    assert!(reloader.reload().is_err());
+    assert!(!updates.has_changed().unwrap());
+    assert_eq!(shared_config.current().amount_history_load, 50);
+
+    shared_config.replace(AppConfig {
+        amount_history_load: 100,
+        ..(*shared_config.current()).clone()
+    });
+    assert!(updates.has_changed().unwrap());
+    assert_eq!(shared_config.current().amount_history_load, 100);
+    std::fs::remove_file(config_file).ok();
+}
+
+
+#[test]
+fn runtime_settings_overrides_test() {
+    let runtime: RuntimeSettings = toml::from_str("fs_check_interval = 500").unwrap();
+    assert_eq!(runtime.fs_check_interval, Some(500));
+    assert_eq!(runtime.webapi_port, None);
+
+    let overridden = runtime
+        .with_overrides(|variable| {
+            match variable {
+                "SMALL_WEBAPI_PORT" => Some(String::from("9000")),
+                _ => None,
+            }
+        })
+        .unwrap();
+    assert_eq!(overridden.fs_check_interval, Some(500));
+    assert_eq!(overridden.webapi_port, Some(9000));
+
+    assert!(
+        runtime
+            .with_overrides(|variable| {
+                (variable == "SMALL_SFTP_BUFFER_SIZE").then(|| String::from("lots"))
+            })
+            .is_err()
+    );
+}
+
+
+#[test]
+fn validate_configs_report_test() {
+    let watch_path = std::env::temp_dir();
+    let ssh_key = watch_path.join(format!("small-test-key-{}", uuid::Uuid::new_v4()));
+    std::fs::write(&ssh_key, "key").unwrap();
+
+    let valid = Config {
+        name: String::from("work"),
+        username: String::from("user"),
+        hostname: String::from("host"),
+        ssh_key: ssh_key.to_string_lossy().to_string(),
+        ssh_port: 22,
+        address: String::from("https://s.example.com/"),
+        remote_path: String::from("/Web/Sshots"),
+        watch_path: watch_path.to_string_lossy().to_string(),
+        active_at: String::from("9:00-16:59"),
+        default: true,
+        ..Config::default()
+    };
+    let mut configs = vec![
+        valid.clone(),
+        Config {
+            name: String::from("evening"),
+            active_at: String::from("17:00:01-8:59:59"),
+            default: false,
+            ..valid.clone()
+        },
+    ];
+    let mut report = ValidationReport::default();
+    validate_configs(&mut configs, &mut report);
+    assert!(report.is_empty(), "{report}");
+    assert_eq!(configs[1].schedule.times.len(), 1);
+
+    let mut configs = vec![
+        valid.clone(),
+        Config {
+            address: String::from("https://s.example.com"),
+            ssh_key: String::from("/nonexistent/key"),
+            watch_path: String::from("/nonexistent/dir"),
+            active_at: String::from("16:00-18:00"),
+            holidays: vec![String::from("tomorrow")],
+            ..valid
+        },
+    ];
+    let mut report = ValidationReport::default();
+    validate_configs(&mut configs, &mut report);
+    let fields: Vec<&str> = report
+        .problems()
+        .iter()
+        .map(|(field, _)| field.as_str())
+        .collect();
+    assert_eq!(
+        fields,
+        vec![
+            "configs[1].address",
+            "configs[1].ssh_key",
+            "configs[1].watch_path",
+            "configs[1].holidays",
+            "configs[1].name",
+            "configs",
+        ]
+    );
+    // NOTE: the invalid schedule isn't taken for the whole day, overlapping the others
+    assert_eq!(configs[1].schedule, Schedule::default());
+    std::fs::remove_file(ssh_key).ok();
+}
+
+
+#[test]
+fn secret_references_test() {
+    let literal = Secret::from("plain-passphrase");
+    assert_eq!(literal.resolve().unwrap(), "plain-passphrase");
+    assert!(!format!("{literal:?}").contains("plain-passphrase"));
+    let config = Config {
+        ssh_key_pass: literal,
+        ..Config::default()
+    };
+    assert!(!format!("{config:?}").contains("plain-passphrase"));
+
+    assert_eq!(
+        Secret::from("env:HOME").resolve().unwrap(),
+        std::env::var("HOME").unwrap()
+    );
+    assert!(Secret::from("env:").check().is_err());
+    assert_eq!(
+        Secret::from("cmd:printf 'from-helper\\n'")
+            .resolve()
+            .unwrap(),
+        "from-helper"
+    );
+    assert!(Secret::from("cmd:exit 1").resolve().is_err());
+
+    use std::os::unix::fs::PermissionsExt;
+    let secret_file =
+        std::env::temp_dir().join(format!("small-test-secret-{}", uuid::Uuid::new_v4()));
+    std::fs::write(&secret_file, "from-file\n").unwrap();
+    let from_file = Secret::from(format!("file:{}", secret_file.display()).as_str());
+    std::fs::set_permissions(&secret_file, std::fs::Permissions::from_mode(0o644)).unwrap();
+    assert!(from_file.check().is_err());
+    assert!(from_file.resolve().is_err());
+    std::fs::set_permissions(&secret_file, std::fs::Permissions::from_mode(0o600)).unwrap();
+    assert_eq!(from_file.resolve().unwrap(), "from-file");
+    std::fs::remove_file(secret_file).ok();
+}
+
+
+#[test]
+fn cli_commands_test() {
+    let cli = Cli::try_parse_from(["small"]).unwrap();
+    assert!(cli.is_daemon());
+
+    let cli = Cli::try_parse_from([
+        "small",
+        "--config",
+        "/tmp/small.toml",
+        "upload",
+        "a.png",
+        "b.png",
+        "--config",
+        "work",
+    ])
+    .unwrap();
+    assert_eq!(
+        cli.config,
+        Some(std::path::PathBuf::from("/tmp/small.toml"))
+    );
+    assert!(matches!(
+        cli.command,
+        Some(Command::Upload { ref files, config: Some(ref name), .. }) if files.len() == 2 && name == "work"
+    ));
+    assert!(!cli.is_daemon());
+
+    let cli =
+        Cli::try_parse_from(["small", "history", "--limit", "2", "--after", "a"]).unwrap();
+    assert!(matches!(
+        cli.command,
+        Some(Command::History {
+            limit: Some(2),
+            ..
+        })
+    ));
+
+    let cli = Cli::try_parse_from(["small", "queue", "drop", "--all"]).unwrap();
+    assert!(matches!(
+        cli.command,
+        Some(Command::Queue {
+            action: Some(QueueAction::Drop {
+                all: true,
+                ..
+            })
+        })
+    ));
+    assert!(Cli::try_parse_from(["small", "queue", "drop"]).is_err());
+    assert!(Cli::try_parse_from(["small", "upload"]).is_err());
+}
+
+
+#[test]
+fn database_search_and_restore_test() {
+    let db_path = test_db_path();
+    let dump_path = db_path.with_extension("dump.db");
+    let database = Database::new(&db_path).unwrap();
+    for (uuid, file) in [("a", "/tmp/screenshot.png"), ("b", "/tmp/notes.txt")] {
+        database
+            .add_history(&History {
+                content: format!("https://s.example.com/{uuid}"),
+                timestamp: 1,
+                file: file.to_string(),
+                uuid: uuid.to_string(),
+                config: None,
+                ..History::default()
+            })
+            .unwrap();
+    }
+
+    let search = |text: &str, limit| {
+        database
+            .search_history(&HistoryFilter {
+                text: Some(text.to_string()),
+                limit,
+                ..HistoryFilter::default()
+            })
+            .unwrap()
+    };
+    let found = search("screenshot", 10);
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].uuid, "a");
+    assert_eq!(search("example", 1).len(), 1);
+    assert!(database.find_history("b").unwrap().is_some());
+    assert!(database.find_history("c").unwrap().is_none());
+
+    database.dump_to_file(&dump_path).unwrap();
+    database
+        .add_to_queue(&QueueItem::new("/tmp/c.png", &Config::default()))
+        .unwrap();
+    assert_eq!(database.clear_queue().unwrap(), 1);
+    let backup = database.restore_from_file(&dump_path).unwrap();
+    assert_eq!(database.get_history(None).unwrap().len(), 2);
+    assert!(backup.starts_with(db_path.parent().unwrap()));
+    remove_test_db(&db_path);
+}
+
+
+#[test]
+fn spool_stream_test() {
+    let spool_dir = test_dir();
+
+    let path = spool_stream(&mut "output".as_bytes(), &spool_dir, None, None).unwrap();
+    assert_eq!(path.file_name().unwrap(), "stdin.txt");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "output");
+
+    let path = spool_stream(
+        &mut "png".as_bytes(),
+        &spool_dir,
+        Some("chart"),
+        Some(".png"),
+    )
+    .unwrap();
+    assert_eq!(file_extension(&path), ".png");
+    assert_eq!(path.file_name().unwrap(), "chart.png");
+
+    let path = spool_stream(
+        &mut "csv".as_bytes(),
+        &spool_dir,
+        Some("../report.csv"),
+        None,
+    )
+    .unwrap();
+    assert!(path.starts_with(&spool_dir));
+    assert_eq!(path.file_name().unwrap(), "report.csv");
+
+    assert!(spool_stream(&mut "".as_bytes(), &spool_dir, None, None).is_err());
+
+    // NOTE: the directory of the spooled file goes away with it, the spool directory stays
+    remove_spooled(&path, &spool_dir).unwrap();
+    assert!(!path.parent().unwrap().exists());
+    assert!(spool_dir.is_dir());
+    std::fs::remove_dir_all(spool_dir).ok();
+}
+
+
+#[test]
+fn control_requests_test() {
+    let request: ControlRequest =
+        serde_json::from_str(r#"{"command":"force_config","name":"night"}"#).unwrap();
+    assert_eq!(
+        request,
+        ControlRequest::ForceConfig {
+            name: Some(String::from("night")),
+        }
+    );
+    assert!(serde_json::from_str::<ControlRequest>(r#"{"command":"unknown"}"#).is_err());
+
+    let db_path = test_db_path();
+    let shared_config = SharedConfig::new(AppConfig {
+        configs: vec![
+            Config {
+                name: String::from("day"),
+                default: true,
+                ..Config::default()
+            },
+            Config {
+                name: String::from("night"),
+                active_at: String::from("00:00:00-00:00:01"),
+                ..Config::default()
+            }
+            .with_schedule()
+            .unwrap(),
+        ],
+        ..AppConfig::default()
+    });
+    let server = ControlServer::new(
+        shared_config.clone(),
+        std::sync::Arc::new(Database::new(&db_path).unwrap()),
+        Supervisor::new(CancellationToken::new(), 5),
+    );
+
+    let status = server
+        .handle(ControlRequest::Pause {
+            until: None,
+        })
+        .unwrap();
+    assert_eq!(status["paused"], true);
+    let status = server.handle(ControlRequest::Resume).unwrap();
+    assert_eq!(status["paused"], false);
+
+    let status = server.handle(request).unwrap();
+    assert_eq!(status["active_config"], "night");
+    assert_eq!(
+        shared_config.current().select_config().unwrap().name,
+        "night"
+    );
+    assert!(
+        server
+            .handle(ControlRequest::ForceConfig {
+                name: Some(String::from("unknown")),
+            })
+            .is_err()
+    );
+    server
+        .handle(ControlRequest::ForceConfig {
+            name: None,
+        })
+        .unwrap();
+    assert_eq!(shared_config.current().forced_config, None);
+    assert_eq!(
+        server.handle(ControlRequest::Queue).unwrap(),
+        serde_json::json!([])
+    );
+
+    remove_test_db(&db_path);
+}
+
+
+#[test]
+fn instance_lock_test() {
+    let lock_path =
+        std::env::temp_dir().join(format!("small-test-{}.pid", uuid::Uuid::new_v4()));
+    // NOTE: a lock left by a crashed daemon is only a file with a stale PID
+    std::fs::write(&lock_path, "999999").unwrap();
+
+    let lock = InstanceLock::acquire(&lock_path).unwrap();
+    let pid = std::process::id().to_string();
+    assert_eq!(std::fs::read_to_string(&lock_path).unwrap(), pid);
+
+    let error = InstanceLock::acquire(&lock_path).unwrap_err().to_string();
+    assert!(error.contains(&format!("PID: {pid}")));
+
+    drop(lock);
+    assert!(InstanceLock::acquire(&lock_path).is_ok());
+    std::fs::remove_file(lock_path).ok();
+}
+
+
+#[test]
+fn pause_state_test() {
+    let now =
+        NaiveDateTime::parse_from_str("2025-06-02 17:30:00", "%Y-%m-%d %H:%M:%S").unwrap();
+    let at = |text: &str| {
+        schedule::parse_until(text, now)
+            .unwrap()
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    };
+    assert_eq!(at("30m"), "2025-06-02 18:00:00");
+    assert_eq!(at("2h"), "2025-06-02 19:30:00");
+    assert_eq!(at("18:00"), "2025-06-02 18:00:00");
+    assert_eq!(at("9:00"), "2025-06-03 09:00:00");
+    assert_eq!(at("2025-06-05 8:15"), "2025-06-05 08:15:00");
+    assert!(schedule::parse_until("soon", now).is_err());
+    for text in ["-5m", "0h", "99999999999d", "9223372036854775807s"] {
+        assert!(schedule::parse_until(text, now).is_err(), "{text}");
+    }
+
+    let pause = PauseState {
+        paused: true,
+        until: Some(100),
+    };
+    assert!(pause.is_active(99));
+    assert!(!pause.is_active(100));
+    assert!(PauseState::until(None).unwrap().is_active(i64::MAX));
+    assert!(!PauseState::default().is_active(0));
+
+    let db_path = test_db_path();
+    let database = Database::new(&db_path).unwrap();
+    assert_eq!(database.get_pause().unwrap(), PauseState::default());
+    database.set_pause(&pause).unwrap();
+    drop(database);
+
+    // NOTE: the pause survives restarts
+    let database = Database::new(&db_path).unwrap();
+    assert_eq!(database.get_pause().unwrap(), pause);
+    database.set_pause(&PauseState::default()).unwrap();
+    assert_eq!(database.get_pause().unwrap(), PauseState::default());
+    remove_test_db(&db_path);
+}
+
+
+#[test]
+fn upload_checkpoint_and_shutdown_test() {
+    let db_path = test_db_path();
+    let database = Database::new(&db_path).unwrap();
+    let item = QueueItem::new("/tmp/big.mov", &Config::default());
+    database.add_to_queue(&item).unwrap();
+    assert_eq!(database.upload_checkpoint(&item.uuid).unwrap(), None);
+
+    database.start_upload(&item.uuid, 1024, 1700000000).unwrap();
+    assert_eq!(
+        database.upload_checkpoint(&item.uuid).unwrap(),
+        Some((1024, 1700000000))
+    );
+    database.flush().unwrap();
+
+    // NOTE: the checkpoint goes away with the uploaded queue item
+    assert_eq!(database.remove_from_queue(&item.uuid).unwrap(), 1);
+    assert_eq!(database.upload_checkpoint(&item.uuid).unwrap(), None);
+    assert_eq!(database.remove_from_queue(&item.uuid).unwrap(), 0);
+    remove_test_db(&db_path);
+
+    assert_eq!(Shutdown::Stop.exit_code(), 0);
+    assert_ne!(Shutdown::Failure.exit_code(), 0);
+}
+
+
+#[tokio::test]
+async fn supervisor_restarts_and_gives_up_test() {
+    assert_eq!(Supervisor::backoff(1), Duration::from_secs(1));
+    assert_eq!(Supervisor::backoff(3), Duration::from_secs(4));
+    assert_eq!(Supervisor::backoff(100), Duration::from_secs(60));
+
+    let supervisor = Supervisor::new(CancellationToken::new(), 1);
+    let handle = supervisor.spawn("Failing", || async { anyhow::bail!("broken") });
+    assert!(handle.await.unwrap().is_err());
+    let status = &supervisor.statuses()["Failing"];
+    assert_eq!(status.restarts, 1);
+    assert_eq!(status.last_error.as_deref(), Some("broken"));
+    assert!(status.gave_up && !status.running);
+
+    // NOTE: a panic is a failure too, not the end of the daemon
+    let supervisor = Supervisor::new(CancellationToken::new(), 0);
+    let handle = supervisor.spawn("Panicking", || async { panic!("oops") });
+    assert!(handle.await.unwrap().is_err());
+    assert!(supervisor.statuses()["Panicking"].gave_up);
+
+    // NOTE: the shutdown stops the subsystem without counting a failure
+    let shutdown = CancellationToken::new();
+    let supervisor = Supervisor::new(shutdown.clone(), 1);
+    let token = shutdown.clone();
+    let handle = supervisor.spawn("Stopping", move || {
+        let token = token.clone();
+        async move {
+            token.cancelled().await;
+            Ok(())
+        }
+    });
+    shutdown.cancel();
+    assert!(handle.await.unwrap().is_ok());
+    assert_eq!(supervisor.statuses()["Stopping"].restarts, 0);
+}
+
+
+#[test]
+fn history_upload_metadata_test() {
+    let dir = test_dir();
+    std::fs::create_dir_all(&dir).unwrap();
+    let image_path = dir.join("shot.png");
+    image::RgbImage::new(32, 16).save(&image_path).unwrap();
+    let text_path = dir.join("notes.txt");
+    std::fs::write(&text_path, "abc").unwrap();
+
+    assert_eq!(image_dimensions(&image_path), Some((32, 16)));
+    assert_eq!(image_dimensions(&text_path), None);
+    assert_eq!(file_mime(&image_path), "image/png");
+    assert_eq!(file_mime(&text_path), "text/plain");
+    assert_eq!(
+        file_sha256(&text_path).unwrap(),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+
+    let entry = History {
+        content: String::from("https://s.example.com/a.png"),
+        timestamp: 1,
+        file: image_path.to_string_lossy().to_string(),
+        uuid: String::from("a"),
+        config: Some(String::from("work")),
+        size: Some(2048),
+        mime: Some(file_mime(&image_path)),
+        sha256: Some(file_sha256(&image_path).unwrap()),
+        width: Some(32),
+        height: Some(16),
+        upload_ms: Some(500),
+        throughput: Some(4096.0),
+        remote_file: Some(String::from("/var/www/a.png")),
+        backend: Some(String::from("sftp")),
+        ..History::default()
+    };
+    let db_path = dir.join("small.db");
+    let database = Database::new(&db_path).unwrap();
+    database.add_history(&entry).unwrap();
+    let stored = database.find_history("a").unwrap().unwrap();
+    assert_eq!(stored.sha256, entry.sha256);
+    assert_eq!(stored.remote_file.as_deref(), Some("/var/www/a.png"));
+    assert_eq!(
+        stored.details(),
+        format!(
+            "2.00KiB, image/png, 32x16, 0.50s at 4.00KiB/s, sftp:/var/www/a.png, sha256:{}",
+            &entry.sha256.unwrap()[..12]
+        )
+    );
+    assert_eq!(History::default().details(), "");
+    std::fs::remove_dir_all(dir).ok();
+}
+
+
+#[test]
+fn history_full_text_search_test() {
+    let db_path = test_db_path();
+    let database = Database::new(&db_path).unwrap();
+    let entries = [
+        (
+            "a",
+            "/Users/me/Desktop/Jira outage.png",
+            1_750_000_000,
+            Some("image/png"),
+        ),
+        (
+            "b",
+            "/Users/me/Desktop/jira-ticket.pdf",
+            1_760_000_000,
+            None,
+        ),
+        (
+            "c",
+            "/Users/me/Desktop/lunch.jpg",
+            1_760_000_100,
+            Some("image/jpeg"),
+        ),
+    ];
+    for (uuid, file, timestamp, mime) in entries {
+        database
+            .add_history(&History {
+                content: format!("https://s.example.com/{uuid}"),
+                timestamp,
+                file: file.to_string(),
+                uuid: uuid.to_string(),
+                mime: mime.map(String::from),
+                ..History::default()
+            })
+            .unwrap();
+    }
+    let search = |filter: HistoryFilter| -> Vec<String> {
+        database
+            .search_history(&HistoryFilter {
+                limit: 10,
+                ..filter
+            })
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.uuid)
+            .collect()
+    };
+    let text = |text: &str| {
+        HistoryFilter {
+            text: Some(text.to_string()),
+            ..HistoryFilter::default()
+        }
+    };
+
+    // NOTE: case insensitive words and their beginnings, newest first
+    assert_eq!(search(text("jira")), ["b", "a"]);
+    assert_eq!(search(text("JIRA out")), ["a"]);
+    assert_eq!(search(text("tick \"")), ["b"]);
+    assert_eq!(search(text("\"-*")), ["c", "b", "a"]);
+    assert!(search(text("dinner")).is_empty());
+
+    assert_eq!(
+        search(HistoryFilter {
+            since: Some(1_755_000_000),
+            ..text("jira")
+        }),
+        ["b"]
+    );
+    assert_eq!(
+        search(HistoryFilter {
+            until: Some(1_760_000_000),
+            ..HistoryFilter::default()
+        }),
+        ["b", "a"]
+    );
+    let kind = |kind: &str| {
+        HistoryFilter {
+            kind: Some(kind.to_string()),
+            ..HistoryFilter::default()
+        }
+    };
+    assert_eq!(search(kind("image")), ["c", "a"]);
+    assert_eq!(search(kind("image/png")), ["a"]);
+    assert_eq!(search(kind(".PDF")), ["b"]);
+    assert!(search(kind("%")).is_empty());
+    assert!(search(kind("_mage")).is_empty());
+    assert!(search(kind("p_f")).is_empty());
+
+    let now =
+        NaiveDateTime::parse_from_str("2025-09-10 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+    let bound = |text, end| {
+        schedule::parse_date_bound(text, now, end)
+            .unwrap()
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    };
+    assert_eq!(bound("7d", false), "2025-09-03 12:00:00");
+    assert_eq!(bound("2025-09-01", false), "2025-09-01 00:00:00");
+    assert_eq!(bound("2025-09-01", true), "2025-09-01 23:59:59");
+    assert_eq!(bound("2025-09-01 18:30", true), "2025-09-01 18:30:00");
+    for text in ["-7d", "99999999999d", "9223372036854775807s"] {
+        assert!(
+            schedule::parse_date_bound(text, now, false).is_err(),
+            "{text}"
+        );
+        assert!(HistoryFilter::new(None, Some(text), None, None, None, 10).is_err());
+    }
+    assert!(HistoryFilter::new(None, Some("last month"), None, None, None, 10).is_err());
+    remove_test_db(&db_path);
+}
+
+
+#[test]
+fn history_tags_and_notes_test() {
+    assert_eq!(
+        database::normalize_tags(&["Design, #ticket-1", " design  Bug "]),
+        ["design", "ticket-1", "bug"]
+    );
+
+    let app_config = AppConfig {
+        auto_tags: vec![AutoTags {
+            path: std::path::PathBuf::from("/Users/me/Exports"),
+            tags: vec![String::from("Design")],
+        }],
+        ..AppConfig::default()
+    };
+    let config = Config {
+        tags: vec![String::from("work"), String::from("design")],
+        ..Config::default()
+    };
+    assert_eq!(
+        app_config.tags_for(&config, "/Users/me/Exports/v2/mockup.png"),
+        ["work", "design"]
+    );
+    assert_eq!(
+        app_config.tags_for(&Config::default(), "/Users/me/Exports-old/a.png"),
+        Vec::<String>::new()
+    );
+
+    let db_path = test_db_path();
+    let database = Database::new(&db_path).unwrap();
+    for uuid in ["a", "b"] {
+        database
+            .add_history(&History {
+                content: format!("https://s.example.com/{uuid}.png"),
+                timestamp: 1,
+                file: format!("/tmp/{uuid}.png"),
+                uuid: uuid.to_string(),
+                tags: vec![String::from("design")],
+                ..History::default()
+            })
+            .unwrap();
+    }
+    assert!(
+        database
+            .set_tags("a", &[String::from("JIRA-42 outage")])
+            .unwrap()
+    );
+    assert!(
+        database
+            .set_note("a", Some(" the login page is down "))
+            .unwrap()
+    );
+    assert!(!database.set_tags("c", &[]).unwrap());
+    let entry = database.find_history("a").unwrap().unwrap();
+    assert_eq!(entry.tags, ["jira-42", "outage"]);
+    assert_eq!(entry.note.as_deref(), Some("the login page is down"));
+
+    let search = |text: Option<&str>, tag: Option<&str>| -> Vec<String> {
+        database
+            .search_history(&HistoryFilter::new(text, None, None, None, tag, 10).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.uuid)
+            .collect()
+    };
+    assert_eq!(search(None, Some("#Design")), ["b"]);
+    assert_eq!(search(None, Some("jira-42")), ["a"]);
+    assert!(search(None, Some("jira")).is_empty());
+    // NOTE: the tags and notes are in the full text index too
+    assert_eq!(search(Some("login"), None), ["a"]);
+    assert_eq!(search(Some("outage"), None), ["a"]);
+
+    assert!(database.set_note("a", Some("")).unwrap());
+    assert_eq!(database.find_history("a").unwrap().unwrap().note, None);
+    remove_test_db(&db_path);
+}
+
+
+#[test]
+fn dumps_retention_test() {
+    let dump = |created: &str| {
+        dumps::Dump {
+            path: std::path::PathBuf::from(format!("{created}.db")),
+            created: NaiveDateTime::parse_from_str(created, "%Y-%m-%d %H:%M").unwrap(),
+            size: 1,
+        }
+    };
+    // NOTE: the newest first, every 6 hours for the last days, older ones sparse
+    let all = [
+        dump("2025-09-10 18:00"),
+        dump("2025-09-10 12:00"),
+        dump("2025-09-10 06:00"),
+        dump("2025-09-09 18:00"),
+        dump("2025-09-09 12:00"),
+        dump("2025-09-02 12:00"),
+        dump("2025-08-20 12:00"),
+        dump("2025-07-01 12:00"),
+    ];
+    let settings = DumpSettings {
+        keep_latest: 2,
+        keep_daily: 2,
+        keep_weekly: 2,
+        keep_monthly: 2,
+        ..DumpSettings::default()
+    };
+    let retained = dumps::retained(&all, &settings);
+    let mut retained: Vec<String> = retained
+        .iter()
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    retained.sort();
+    assert_eq!(
+        retained,
+        [
+            "2025-08-20 12:00.db", // monthly
+            "2025-09-02 12:00.db", // weekly
+            "2025-09-09 18:00.db", // daily
+            "2025-09-10 12:00.db", // latest
+            "2025-09-10 18:00.db", // latest, daily, weekly, monthly
+        ]
+    );
+    let nothing = DumpSettings {
+        keep_latest: 0,
+        keep_daily: 0,
+        keep_weekly: 0,
+        keep_monthly: 0,
+        ..DumpSettings::default()
+    };
+    assert_eq!(dumps::retained(&all, &nothing).len(), 1);
+
+    let dir = test_dir();
+    let dumps_dir = dir.join("dumps");
+    let database = Database::new(dir.join("small.db")).unwrap();
+    let settings = DumpSettings {
+        compress: true,
+        ..DumpSettings::default()
+    };
+    let first = dumps::create(&database, &dumps_dir, &settings, true)
+        .unwrap()
+        .unwrap();
+    assert!(first.to_string_lossy().ends_with(".db.gz"));
+    std::fs::write(dumps_dir.join("notes.txt"), "not a dump").unwrap();
+
+    // NOTE: the unchanged database isn't dumped again, unless asked to
+    assert_eq!(
+        dumps::create(&database, &dumps_dir, &settings, true).unwrap(),
+        None
+    );
+    assert_eq!(dumps::list(&dumps_dir).unwrap().len(), 1);
+    database
+        .add_history(&History {
+            content: String::from("https://s.example.com/a.png"),
+            file: String::from("/tmp/a.png"),
+            uuid: String::from("a"),
+            ..History::default()
+        })
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(1100));
+    let second = dumps::create(&database, &dumps_dir, &settings, true)
+        .unwrap()
+        .unwrap();
+    assert_ne!(first, second);
+    let (count, size) = dumps::disk_usage(&dumps_dir).unwrap();
+    assert_eq!(count, 2);
+    assert!(size > 0);
+
+    // NOTE: the gzipped dumps are restored too
+    database.restore_from_file(&first).unwrap();
+    assert!(database.get_history(None).unwrap().is_empty());
+    database.restore_from_file(&second).unwrap();
+    assert_eq!(database.get_history(None).unwrap().len(), 1);
+    assert!(dumps_dir.join("notes.txt").exists());
+    std::fs::remove_dir_all(dir).ok();
+}
+
+
+#[test]
+fn restore_dump_test() {
+    let dir = test_dir();
+    let dumps_dir = dir.join("dumps");
+    let db_path = dir.join("small.db");
+    let database = std::sync::Arc::new(Database::new(&db_path).unwrap());
+    let entry = |uuid: &str| {
+        History {
+            content: format!("https://s.example.com/{uuid}.png"),
+            file: format!("/tmp/{uuid}.png"),
+            uuid: uuid.to_string(),
+            ..History::default()
+        }
+    };
+    database.add_history(&entry("a")).unwrap();
+    let dump = dumps::create(&database, &dumps_dir, &DumpSettings::default(), false)
+        .unwrap()
+        .unwrap();
+    database.add_history(&entry("b")).unwrap();
+    database
+        .add_to_queue(&QueueItem::new("/tmp/c.png", &Config::default()))
+        .unwrap();
+
+    let listed = dumps::inspect_all(&dumps_dir).unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!((listed[0].history, listed[0].queue), (1, 0));
+    assert_eq!(listed[0].version, migrations::latest_version());
+    assert_eq!(
+        dumps::resolve(&dumps_dir, std::path::Path::new("latest")).unwrap(),
+        dump
+    );
+    assert_eq!(
+        dumps::resolve(&dumps_dir, dump.file_name().unwrap().as_ref()).unwrap(),
+        dump
+    );
+    assert!(dumps::resolve(&dumps_dir, std::path::Path::new("missing.db")).is_err());
+
+    // NOTE: a damaged dump is refused before anything is changed
+    let damaged = dir.join("damaged.db");
+    std::fs::write(&damaged, "not a database").unwrap();
+    assert!(database.restore_from_file(&damaged).is_err());
+    assert_eq!(database.get_history(None).unwrap().len(), 2);
+
+    // NOTE: the gzipped dump is decompressed readable only by the user
+    use std::os::unix::fs::PermissionsExt;
+    let gzipped = dir.join("gzipped.db.gz");
+    let mut encoder = flate2::write::GzEncoder::new(
+        std::fs::File::create(&gzipped).unwrap(),
+        flate2::Compression::default(),
+    );
+    std::io::copy(&mut std::fs::File::open(&dump).unwrap(), &mut encoder).unwrap();
+    encoder.finish().unwrap();
+    let decompressed = dumps::decompress(&gzipped).unwrap();
+    let mode = std::fs::metadata(&decompressed)
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+    std::fs::remove_file(decompressed).unwrap();
+    let gzip = std::fs::read(&gzipped).unwrap();
+    std::fs::write(&gzipped, &gzip[..gzip.len() / 2]).unwrap();
+    assert!(dumps::decompress(&gzipped).is_err());
+
+    // NOTE: the running daemon restores through the control socket
+    let server = ControlServer::new(
+        SharedConfig::new(AppConfig::default()),
+        database.clone(),
+        Supervisor::new(CancellationToken::new(), 5),
+    );
+    let result = server
+        .handle(ControlRequest::Restore {
+            dump: dump.clone(),
+        })
+        .unwrap();
+    assert_eq!(database.get_history(None).unwrap().len(), 1);
+    assert_eq!(database.queue_length().unwrap(), 0);
+
+    let backup = Database::new(result["backup"].as_str().unwrap()).unwrap();
+    assert_eq!(backup.get_history(None).unwrap().len(), 2);
+    assert_eq!(backup.queue_length().unwrap(), 1);
+    std::fs::remove_dir_all(dir).ok();
+}
+
+
+#[test]
+fn export_and_import_test() {
+    let dir = test_dir();
+    std::fs::create_dir_all(&dir).unwrap();
+    let database = Database::new(dir.join("source.db")).unwrap();
+    let (a, b) = (
+        uuid::Uuid::new_v4().to_string(),
+        uuid::Uuid::new_v4().to_string(),
+    );
+    database
+        .add_history(&History {
+            content: String::from("https://s.example.com/a.png"),
+            timestamp: 2,
+            file: String::from("/tmp/shot, final.png"),
+            uuid: a.clone(),
+            mime: Some(String::from("image/png")),
+            sha256: Some(String::from("aaaa")),
+            tags: vec![String::from("client")],
+            note: Some(String::from("the <new> \"logo\"")),
+            ..History::default()
+        })
+        .unwrap();
+    database
+        .add_history(&History {
+            content: String::from("https://s.example.com/b.pdf"),
+            timestamp: 1,
+            file: String::from("/tmp/b.pdf"),
+            uuid: b.clone(),
+            ..History::default()
+        })
+        .unwrap();
+    let local_file = dir.join("queued.txt");
+    std::fs::write(&local_file, "queued").unwrap();
+    for local_file in [
+        local_file.to_string_lossy().to_string(),
+        String::from("/missing"),
+    ] {
+        database
+            .add_to_queue(&QueueItem::new(&local_file, &Config::default()))
+            .unwrap();
+    }
+
+    let all = HistoryFilter::new(None, None, None, None, None, usize::MAX).unwrap();
+    let exported = |format: Format, queue: bool| -> String {
+        let mut output = Vec::new();
+        export::export(&database, &all, format, queue, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+    let csv = exported(Format::Csv, false);
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("date,timestamp,uuid,file,content"));
+    assert!(lines[1].contains(&format!(
+        r#",{a},"/tmp/shot, final.png",https://s.example.com/a.png,"#
+    )));
+    assert!(lines[1].ends_with(r#",client,"the <new> ""logo""""#));
+
+    let html = exported(Format::Html, false);
+    assert!(html.contains(r#"<img src="https://s.example.com/a.png""#));
+    assert!(html.contains("the &lt;new&gt; &quot;logo&quot;"));
+    assert!(!html.contains("<link") && !html.contains("<script"));
+    let mut output = Vec::new();
+    assert!(export::export(&database, &all, Format::Csv, true, &mut output).is_err());
+    assert_eq!(
+        Format::from_path(std::path::Path::new("report.HTML")),
+        Some(Format::Html)
+    );
+
+    let jsonl = exported(Format::JsonLines, true);
+    assert_eq!(jsonl.lines().count(), 4);
+    assert!(jsonl.starts_with(r#"{"kind":"history","content":"https://s.example.com/a.png""#));
+
+    // NOTE: the entries already there are merged, by the uuid or by the content hash
+    let target = Database::new(dir.join("target.db")).unwrap();
+    target
+        .add_history(&History {
+            content: String::from("https://other.example.com/x.png"),
+            timestamp: 3,
+            file: String::from("/Users/me/shot.png"),
+            uuid: String::from("x"),
+            sha256: Some(String::from("aaaa")),
+            tags: vec![String::from("mine")],
+            ..History::default()
+        })
+        .unwrap();
+    let records = export::read_records(jsonl.as_bytes()).unwrap();
+    assert_eq!(
+        export::import(&target, records.clone()).unwrap(),
+        ImportReport {
+            added: 1,
+            merged: 1,
+            unchanged: 0,
+            queued: 1,
+        }
+    );
+    let merged = target.find_history("x").unwrap().unwrap();
+    assert_eq!(merged.tags, ["mine", "client"]);
+    assert_eq!(merged.note.as_deref(), Some("the <new> \"logo\""));
+    assert!(target.find_history(&a).unwrap().is_none());
+    assert_eq!(target.get_history(None).unwrap().len(), 2);
+    assert_eq!(
+        export::import(&target, records).unwrap(),
+        ImportReport {
+            added: 0,
+            merged: 0,
+            unchanged: 2,
+            queued: 0,
+        }
+    );
+
+    // NOTE: the JSON array of: small history --json is imported too
+    let array = serde_json::to_string_pretty(&database.get_history(None).unwrap()).unwrap();
+    let records = export::read_records(array.as_bytes()).unwrap();
+    assert_eq!(records.len(), 2);
+    let fresh = Database::new(dir.join("fresh.db")).unwrap();
+    assert_eq!(export::import(&fresh, records).unwrap().added, 2);
+    assert!(export::read_records("{\"kind\":\"unknown\"}".as_bytes()).is_err());
+
+    // NOTE: nothing is imported when any of the records is invalid
+    let mut records = export::read_records(jsonl.as_bytes()).unwrap();
+    if let export::Record::History(entry) = &mut records[1] {
+        entry.uuid = String::from("\"><script>alert(1)</script>");
+    }
+    let empty = Database::new(dir.join("empty.db")).unwrap();
+    assert!(export::import(&empty, records).is_err());
+    assert!(empty.get_history(None).unwrap().is_empty());
+    assert_eq!(empty.queue_length().unwrap(), 0);
+    std::fs::remove_dir_all(dir).ok();
+}
+
+
+#[test]
+fn history_duplicates_and_pages_test() {
+    let db_path = test_db_path();
+    let database = Database::new(&db_path).unwrap();
+    for (index, uuid) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+        database
+            .add_history(&History {
+                content: format!("https://s.example.com/{uuid}.png"),
+                timestamp: if index < 3 { 1 } else { 2 },
+                file: format!("/tmp/{uuid}.png"),
+                uuid: uuid.to_string(),
+                sha256: Some(String::from("same")),
+                ..History::default()
+            })
+            .unwrap();
+    }
+
+    // NOTE: the link is unique, the second entry of it is ignored
+    database
+        .add_history(&History {
+            content: String::from("https://s.example.com/a.png"),
+            timestamp: 3,
+            file: String::from("/tmp/again.png"),
+            uuid: String::from("again"),
+            ..History::default()
+        })
+        .unwrap();
+    assert!(database.find_history("again").unwrap().is_none());
+    assert_eq!(
+        database
+            .find_history_by_content("https://s.example.com/b.png")
+            .unwrap()
+            .unwrap()
+            .uuid,
+        "b"
+    );
+    assert!(
+        database
+            .find_history_by_content("https://s.example.com/b")
+            .unwrap()
+            .is_none()
+    );
+    assert_eq!(
+        database
+            .find_history_by_sha256("same")
+            .unwrap()
+            .unwrap()
+            .uuid,
+        "e"
+    );
+
+    // NOTE: the keyset pages go through the entries of the same timestamp too
+    let mut paged = Vec::new();
+    let mut after = None;
+    loop {
+        let page = database
+            .search_history(&HistoryFilter {
+                after,
+                limit: 2,
+                ..HistoryFilter::default()
+            })
+            .unwrap();
+        let Some(last) = page.last() else {
+            break;
+        };
+        after = Some(last.uuid.clone());
+        paged.extend(page.into_iter().map(|entry| entry.uuid));
+    }
+    assert_eq!(paged, ["e", "d", "c", "b", "a"]);
+    let after_c = HistoryFilter {
+        kind: Some(String::from("png")),
+        after: Some(String::from("c")),
+        limit: 10,
+        ..HistoryFilter::default()
+    };
+    let older: Vec<String> = database
+        .search_history(&after_c)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.uuid)
+        .collect();
+    assert_eq!(older, ["b", "a"]);
+    assert!(
+        database
+            .search_history(&HistoryFilter {
+                after: Some(String::from("missing")),
+                limit: 10,
+                ..HistoryFilter::default()
+            })
+            .is_err()
+    );
+    assert_eq!(database.get_history(Some(1)).unwrap()[0].uuid, "e");
+    remove_test_db(&db_path);
+}
+
+
+#[tokio::test(flavor = "multi_thread")]
+async fn database_wal_readers_test() {
+    let dir = test_dir();
+    let db_path = dir.join("small.db");
+    let database = std::sync::Arc::new(Database::new(&db_path).unwrap());
+    let journal_mode = |path: &std::path::Path| -> String {
+        rusqlite::Connection::open(path)
+            .unwrap()
+            .pragma_query_value(None, "journal_mode", |row| row.get(0))
+            .unwrap()
+    };
+    assert_eq!(journal_mode(&db_path), "wal");
+    database
+        .add_history(&History {
+            content: String::from("https://s.example.com/a.png"),
+            timestamp: 1,
+            file: String::from("/tmp/a.png"),
+            uuid: String::from("a"),
+            ..History::default()
+        })
+        .unwrap();
+
+    // NOTE: the readers aren't stopped by a write in progress, they read the committed content
+    let other = rusqlite::Connection::open(&db_path).unwrap();
+    other
+        .execute_batch(
+            "BEGIN IMMEDIATE; INSERT INTO history (content, timestamp, file, uuid) VALUES ('https://s.example.com/b.png', 2, '/tmp/b.png', 'b');",
+        )
+        .unwrap();
+    let read = database
+        .call(|database| database.get_history(None))
+        .await
+        .unwrap();
+    assert_eq!(read.len(), 1);
+
+    // NOTE: the writer waits for the lock by the busy timeout instead of failing
+    let committing = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(300));
+        other.execute_batch("COMMIT").unwrap();
+    });
+    database
+        .call(|database| database.set_note("a", Some("waited")))
+        .await
+        .unwrap();
+    committing.join().unwrap();
+    let read = database
+        .call(|database| database.get_history(None))
+        .await
+        .unwrap();
+    assert_eq!(read.len(), 2);
+    assert_eq!(read[1].note.as_deref(), Some("waited"));
+
+    // NOTE: the dumps are single files, the restored database stays in the WAL mode
+    let dump = dir.join("dump.db");
+    database.dump_to_file(&dump).unwrap();
+    assert_eq!(journal_mode(&dump), "delete");
+    database.restore_from_file(&dump).unwrap();
+    assert_eq!(journal_mode(&db_path), "wal");
+    assert_eq!(database.get_history(None).unwrap().len(), 2);
+    std::fs::remove_dir_all(dir).ok();
+}
+
+
+#[test]
+fn webapi_upload_request_test() {
+    let dir = test_dir();
+    let watch_path = dir.join("watched");
+    std::fs::create_dir_all(&watch_path).unwrap();
+    let shot = watch_path.join("shot.png");
+    std::fs::write(&shot, "png").unwrap();
+    let outside = dir.join("id_ed25519");
+    std::fs::write(&outside, "key").unwrap();
+    let app_config = AppConfig {
+        configs: vec![Config {
+            watch_path: watch_path.to_string_lossy().to_string(),
+            ..Config::default()
+        }],
+        webapi_port: 8000,
+        ..AppConfig::default()
+    };
+    assert!(app_config.is_watched(&shot));
+    assert!(!app_config.is_watched(&outside));
+    assert!(!app_config.is_watched(&watch_path.join("../id_ed25519")));
+
+    let body = br#"{"files": ["/tmp/a.png"]}"#;
+    let request = |content_type: Option<&str>, origin: Option<&str>| {
+        WebApi::upload_request(&app_config, content_type, origin, body)
+    };
+    assert_eq!(
+        request(Some("application/json"), None).unwrap().files,
+        ["/tmp/a.png"]
+    );
+    assert!(
+        request(
+            Some("Application/JSON; charset=utf-8"),
+            Some("http://localhost:8000")
+        )
+        .is_ok()
+    );
+    // NOTE: the no-cors requests of other pages are sent as text/plain, with their Origin
+    assert!(request(None, None).is_err());
+    assert!(request(Some("text/plain"), None).is_err());
+    assert!(request(Some("application/json"), Some("https://evil.example.com")).is_err());
+    assert!(request(Some("application/json"), Some("http://localhost:8001")).is_err());
+    std::fs::remove_dir_all(dir).ok();
+}
+
+
+#[test]
+fn dashboard_escaping_test() {
+    let db_path = test_db_path();
+    let api = WebApi::new(
+        SharedConfig::new(AppConfig::default()),
+        std::sync::Arc::new(Database::new(&db_path).unwrap()),
+        Supervisor::new(CancellationToken::new(), 5),
+    );
+    let entry = History {
+        content: String::from(
+            r#"javascript:alert(1) https://s.example.com/a.png"onload="alert(2)"#,
+        ),
+        timestamp: 1,
+        file: String::from("/tmp/<img src=x onerror=alert(3)>.png"),
+        uuid: String::from(r#""><script>alert(4)</script>"#),
+        config: Some(String::from("<b>work</b>")),
+        mime: Some(String::from("image/<svg>")),
+        note: Some(String::from("<i>note</i>")),
+        ..History::default()
+    };
+    let html = api.render_history(&[entry], &std::collections::HashMap::new(), true);
+    assert!(!html.contains("<script>alert"));
+    assert!(!html.contains("<img src=x"));
+    assert!(!html.contains("<b>work") && !html.contains("<svg>") && !html.contains("<i>note"));
+    assert!(!html.contains("javascript:"));
+    assert!(!html.contains(r#""onload=""#));
+    assert!(html.contains(r#"href="https://s.example.com/a.png&quot;onload=&quot;alert(2)""#));
+    assert!(html.contains("&lt;b&gt;work&lt;/b&gt;"));
+    assert!(html.contains(
+        r#"<input type="hidden" name="after" value="&quot;&gt;&lt;script&gt;alert(4)&lt;/script&gt;">"#
+    ));
+    remove_test_db(&db_path);
+}
+
+
+#[tokio::test]
+async fn dashboard_errors_escaping_test() {
+    let db_path = test_db_path();
+    let routes = std::sync::Arc::new(WebApi::new(
+        SharedConfig::new(AppConfig::default()),
+        std::sync::Arc::new(Database::new(&db_path).unwrap()),
+        Supervisor::new(CancellationToken::new(), 5),
+    ))
+    .routes();
+    let response = warp::test::request()
+        .path("/?since=%3Cscript%3Ealert(1)%3C/script%3E")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 400);
+    let body = String::from_utf8_lossy(response.body());
+    assert!(!body.contains("<script>"), "{body}");
+    assert!(
+        body.contains("&lt;script&gt;alert(1)&lt;/script&gt;"),
+        "{body}"
+    );
+
+    // The uuid of a missing page start is in the error as well
+    let response = warp::test::request()
+        .path("/?after=%3Cimg%20src=x%20onerror=alert(2)%3E")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 400);
+    let body = String::from_utf8_lossy(response.body());
+    assert!(!body.contains("<img"), "{body}");
+    assert!(
+        body.contains("&lt;img src=x onerror=alert(2)&gt;"),
+        "{body}"
+    );
+
+    let response = warp::test::request().path("/").reply(&routes).await;
+    assert_eq!(response.status(), 200);
+    remove_test_db(&db_path);
+}
+
+
+#[tokio::test]
+async fn dashboard_pause_origin_test() {
+    let db_path = test_db_path();
+    let database = std::sync::Arc::new(Database::new(&db_path).unwrap());
+    let routes = std::sync::Arc::new(WebApi::new(
+        SharedConfig::new(AppConfig {
+            webapi_port: 8000,
+            ..AppConfig::default()
+        }),
+        database.clone(),
+        Supervisor::new(CancellationToken::new(), 5),
+    ))
+    .routes();
+    let paused = || {
+        database
+            .get_pause()
+            .unwrap()
+            .is_active(Local::now().timestamp())
+    };
+
+    // Other web pages can't pause nor resume the uploads
+    let response = warp::test::request()
+        .method("POST")
+        .path("/pause")
+        .header("origin", "https://evil.example.com")
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body("until=")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 400);
+    assert!(!paused());
+
+    let response = warp::test::request()
+        .method("POST")
+        .path("/pause")
+        .header("origin", "http://localhost:8000")
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body("until=")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 303);
+    assert!(paused());
+
+    let response = warp::test::request()
+        .method("POST")
+        .path("/resume")
+        .header("origin", "https://evil.example.com")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 400);
+    assert!(paused());
+
+    // Without an Origin, e.g. curl
+    let response = warp::test::request()
+        .method("POST")
+        .path("/resume")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 303);
+    assert!(!paused());
+    remove_test_db(&db_path);
+}
+
+
+#[tokio::test]
+async fn dashboard_annotate_test() {
+    let db_path = test_db_path();
+    let database = std::sync::Arc::new(Database::new(&db_path).unwrap());
+    database
+        .add_history(&History {
+            content: String::from("https://s.example.com/a.png"),
+            timestamp: 1,
+            file: String::from("/tmp/a.png"),
+            uuid: String::from("a"),
+            ..History::default()
+        })
+        .unwrap();
+    let routes = std::sync::Arc::new(WebApi::new(
+        SharedConfig::new(AppConfig {
+            webapi_port: 8000,
+            amount_history_load: 10,
+            ..AppConfig::default()
+        }),
+        database.clone(),
+        Supervisor::new(CancellationToken::new(), 5),
+    ))
+    .routes();
+    let annotate = |origin: &str| {
+        warp::test::request()
+            .method("POST")
+            .path("/history/a")
+            .header("origin", origin)
+            .header("referer", "https://evil.example.com/")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("tags=r%26d+c%23%2Bx&note=a+note")
+    };
+
+    let response = annotate("https://evil.example.com").reply(&routes).await;
+    assert_eq!(response.status(), 400);
+    let stored = database.find_history("a").unwrap().unwrap();
+    assert!(stored.tags.is_empty());
+    assert_eq!(stored.note, None);
+
+    // Back to the entry on the dashboard, wherever the Referer points
+    let response = annotate("http://127.0.0.1:8000").reply(&routes).await;
+    assert_eq!(response.status(), 303);
+    assert_eq!(response.headers()["location"], "/#a");
+    let stored = database.find_history("a").unwrap().unwrap();
+    assert_eq!(stored.tags, ["r&d", "c#+x"]);
+    assert_eq!(stored.note.as_deref(), Some("a note"));
+
+    let response = warp::test::request().path("/").reply(&routes).await;
+    let body = String::from_utf8_lossy(response.body());
+    assert!(
+        body.contains(r#"<a href="/?tag=r%26d">#r&amp;d</a>"#),
+        "{body}"
+    );
+    assert!(
+        body.contains(r#"<a href="/?tag=c%23%2Bx">#c#+x</a>"#),
+        "{body}"
+    );
+
+    let response = warp::test::request()
+        .path("/?tag=c%23%2Bx")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 200);
+    let body = String::from_utf8_lossy(response.body());
+    assert!(body.contains("https://s.example.com/a.png"), "{body}");
+    remove_test_db(&db_path);
+}
@@ -1,9 +1,10 @@
 use crate::*;
 use anyhow::Result;
+use sha2::{Digest, Sha256};
 use std::{
     fs,
     io::{self, Read, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 use tracing_subscriber::{EnvFilter, fmt};
 
@@ -25,6 +26,22 @@ pub fn initialize() {
 }
 
 
+/// Initialize the instruments-subscriber of the command line, logging warnings to the stderr
+pub fn initialize_cli() {
+    let env_log = match EnvFilter::try_from_env("LOG") {
+        Ok(env_value_from_env) => env_value_from_env,
+        Err(_) => EnvFilter::from("warn"),
+    };
+    fmt()
+        .compact()
+        .with_thread_names(false)
+        .with_thread_ids(false)
+        .with_writer(io::stderr)
+        .with_env_filter(env_log)
+        .init();
+}
+
+
 pub fn put_to_clipboard(text: &str) -> Result<()> {
     let mut clipboard = clippers::Clipboard::get();
     clipboard.write_text(text)?;
@@ -38,11 +55,92 @@ pub fn local_file_size<P: AsRef<Path>>(file_path: P) -> Result<u64> {
 }
 
 
+/// Modification time of the file in seconds since the epoch
+pub fn local_file_mtime<P: AsRef<Path>>(file_path: P) -> Result<i64> {
+    let modified = fs::metadata(file_path)?.modified()?;
+    let since_epoch = modified
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(since_epoch.as_secs() as i64)
+}
+
+
 pub fn size_kib(size_in_bytes: u64) -> f64 {
     (size_in_bytes as f64) / 1024.0
 }
 
 
+/// Human readable size, e.g. "1.25MiB"
+pub fn human_size(size_in_bytes: u64) -> String {
+    let kib = size_kib(size_in_bytes);
+    if kib < 1024.0 {
+        format!("{kib:.2}KiB")
+    } else {
+        format!("{:.2}MiB", kib / 1024.0)
+    }
+}
+
+
+/// Hex encoded SHA-256 of the file content
+pub fn file_sha256<P: AsRef<Path>>(file_path: P) -> Result<String> {
+    let mut file = fs::File::open(file_path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+
+/// MIME type guessed by the file extension
+pub fn file_mime<P: AsRef<Path>>(file_path: P) -> String {
+    mime_guess::from_path(file_path)
+        .first_or_octet_stream()
+        .essence_str()
+        .to_string()
+}
+
+
+/// Width and height of the image file, None for other files
+pub fn image_dimensions<P: AsRef<Path>>(file_path: P) -> Option<(u32, u32)> {
+    imagesize::size(file_path)
+        .ok()
+        .and_then(|size| Some((size.width.try_into().ok()?, size.height.try_into().ok()?)))
+}
+
+
+/// Escapes the text put into the HTML
+pub fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+
+/// Percent-encodes the text put into a query parameter of a link
+pub fn url_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| {
+            if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+                char::from(byte).to_string()
+            } else {
+                format!("%{byte:02X}")
+            }
+        })
+        .collect()
+}
+
+
+/// True for the http and https links, the only ones put into the href and src of the pages
+pub fn is_web_link(link: &str) -> bool {
+    let link = link.to_lowercase();
+    link.starts_with("https://") || link.starts_with("http://")
+}
+
+
 pub fn file_extension<P: AsRef<Path>>(path: P) -> String {
     path.as_ref()
         .extension()
@@ -52,6 +150,72 @@ pub fn file_extension<P: AsRef<Path>>(path: P) -> String {
 }
 
 
+/// Writes the stream to a new file in the spool directory and returns its path.
+/// The file is named after the name (or "stdin") with the extension, ".txt" by default.
+/// Each spool gets its own timestamped directory, so the same name never reuses a link.
+pub fn spool_stream<R: Read>(
+    reader: &mut R,
+    spool_dir: &Path,
+    name: Option<&str>,
+    ext: Option<&str>,
+) -> Result<PathBuf> {
+    let name = name
+        .and_then(|name| Path::new(name).file_name())
+        .and_then(|name| name.to_str())
+        .unwrap_or("stdin");
+    let file_name = match ext.map(|ext| ext.trim_start_matches('.')) {
+        Some(ext) if !ext.is_empty() => {
+            let stem = Path::new(name)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or(name);
+            format!("{stem}.{ext}")
+        }
+        _ if !file_extension(name).is_empty() => name.to_string(),
+        _ => format!("{name}.txt"),
+    };
+
+    // NOTE: the spools of the same millisecond get the numbered directories
+    fs::create_dir_all(spool_dir)?;
+    let timestamp = chrono::Local::now().format("%Y-%m-%d-%H-%M-%S-%3f");
+    let mut dir = spool_dir.join(timestamp.to_string());
+    let mut number = 1;
+    loop {
+        match fs::create_dir(&dir) {
+            Ok(()) => break,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                dir = spool_dir.join(format!("{timestamp}-{number}"));
+                number += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let file_path = dir.join(file_name);
+    let mut file = fs::File::create(&file_path)?;
+    let size = io::copy(reader, &mut file)?;
+    if size == 0 {
+        drop(file);
+        fs::remove_dir_all(&dir).ok();
+        anyhow::bail!("Nothing to upload, the stream is empty");
+    }
+    info!("Spooled {:.2}KiB to: {file_path:?}", size_kib(size));
+    Ok(file_path)
+}
+
+
+/// Removes the spooled file and its directories left empty, up to the spool directory
+pub fn remove_spooled(file_path: &Path, spool_dir: &Path) -> Result<()> {
+    fs::remove_file(file_path)?;
+    debug!("Removed the spooled file: {file_path:?}");
+    for dir in file_path.ancestors().skip(1) {
+        if dir == spool_dir || !dir.starts_with(spool_dir) || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+
 pub fn stream_file_to_remote<R, W>(
     reader: &mut R,
     writer: &mut W,
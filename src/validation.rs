@@ -0,0 +1,197 @@
+use crate::{config::Config, schedule::Schedule, *};
+use anyhow::Result;
+use std::{fmt, fs::File, path::Path};
+
+
+/// All problems found in the configuration, reported at once
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    problems: Vec<(String, String)>,
+}
+
+
+impl ValidationReport {
+    pub fn push(&mut self, field: impl fmt::Display, problem: impl fmt::Display) {
+        self.problems.push((field.to_string(), problem.to_string()));
+    }
+
+
+    /// Records the error of the result under the field name
+    pub fn check<T>(&mut self, field: impl fmt::Display, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.push(field, format!("{e:#}"));
+                None
+            }
+        }
+    }
+
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+
+    pub fn problems(&self) -> &[(String, String)] {
+        &self.problems
+    }
+
+
+    pub fn into_result(self, config_file: &Path) -> Result<()> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            anyhow::bail!("Invalid configuration file: {config_file:?}\n{self}")
+        }
+    }
+}
+
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.problems.len();
+        writeln!(f, "Found {count} configuration problem(s):")?;
+        for (field, problem) in &self.problems {
+            writeln!(f, "  - {field}: {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Validates every config entry and the relations between them.
+/// Parsed schedules are stored in the configs.
+pub fn validate_configs(configs: &mut [Config], report: &mut ValidationReport) {
+    if configs.is_empty() {
+        report.push("configs", "at least one [[configs]] entry is required");
+        return;
+    }
+
+    let scheduled: Vec<bool> = configs
+        .iter_mut()
+        .enumerate()
+        .map(|(index, config)| validate_config(index, config, report))
+        .collect();
+
+    for (index, config) in configs.iter().enumerate() {
+        if let Some(first) = configs[..index]
+            .iter()
+            .position(|other| !config.name.is_empty() && other.name == config.name)
+        {
+            report.push(
+                format!("configs[{index}].name"),
+                format!("{:?} is already used by configs[{first}]", config.name),
+            );
+        }
+    }
+
+    let defaults: Vec<String> = configs
+        .iter()
+        .enumerate()
+        .filter(|(_, config)| config.default)
+        .map(|(index, _)| format!("configs[{index}]"))
+        .collect();
+    match defaults.len() {
+        0 => {
+            report.push(
+                "configs",
+                "exactly one config needs: default = true, found none",
+            )
+        }
+        1 => (),
+        _ => {
+            report.push(
+                "configs",
+                format!(
+                    "exactly one config needs: default = true, found: {}",
+                    defaults.join(", ")
+                ),
+            )
+        }
+    }
+
+    // NOTE: a default config with an empty active_at is the fallback, it can't overlap.
+    // Configs with an invalid schedule are left out, their problem is reported already.
+    let scheduled: Vec<(usize, &Config)> = configs
+        .iter()
+        .enumerate()
+        .filter(|(index, config)| {
+            scheduled[*index] && !(config.default && config.active_at.trim().is_empty())
+        })
+        .collect();
+    for (position, (index, config)) in scheduled.iter().enumerate() {
+        for (other_index, other) in &scheduled[position + 1..] {
+            if config.schedule.overlaps(&other.schedule) {
+                report.push(
+                    format!("configs[{index}].active_at"),
+                    format!(
+                        "schedule overlaps with configs[{other_index}] ({:?} and {:?})",
+                        config.active_at, other.active_at
+                    ),
+                );
+            }
+        }
+    }
+}
+
+
+/// Validates the fields of the config, true when its schedule was parsed
+fn validate_config(index: usize, config: &mut Config, report: &mut ValidationReport) -> bool {
+    let field = |name: &str| format!("configs[{index}].{name}");
+
+    for (name, value) in [
+        ("name", &config.name),
+        ("username", &config.username),
+        ("hostname", &config.hostname),
+        ("remote_path", &config.remote_path),
+        ("watch_path", &config.watch_path),
+    ] {
+        if value.trim().is_empty() {
+            report.push(field(name), "is empty");
+        }
+    }
+    if config.ssh_port == 0 {
+        report.push(field("ssh_port"), "is zero");
+    }
+
+    if config.address.is_empty() {
+        report.push(field("address"), "is empty");
+    } else if !ADDRESS_PATTERN.is_match(&config.address) {
+        report.push(
+            field("address"),
+            format!(
+                "{:?} isn't a valid http(s) URL ending with '/'",
+                config.address
+            ),
+        );
+    }
+
+    let ssh_key = config.ssh_key_path();
+    if let Err(e) = File::open(&ssh_key) {
+        report.push(field("ssh_key"), format!("{ssh_key:?} isn't readable: {e}"));
+    }
+
+    report.check(field("ssh_key_pass"), config.ssh_key_pass.check());
+
+    if !config.watch_path.trim().is_empty() && !Path::new(&config.watch_path).is_dir() {
+        report.push(
+            field("watch_path"),
+            format!("{:?} isn't an existing directory", config.watch_path),
+        );
+    }
+
+    // NOTE: the parts are checked one by one to report the field of the problem
+    let times = report.check(field("active_at"), Schedule::parse_times(&config.active_at));
+    let dates = report.check(
+        field("active_dates"),
+        Schedule::parse_dates(&config.active_dates),
+    );
+    let holidays = report.check(field("holidays"), Schedule::parse_dates(&config.holidays));
+    times.is_some()
+        && dates.is_some()
+        && holidays.is_some()
+        && report
+            .check(field("active_at"), config.parse_schedule())
+            .is_some()
+}
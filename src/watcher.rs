@@ -1,26 +1,28 @@
 use crate::{
-    config::AppConfig,
+    config::{DeletePolicy, SharedConfig, WatcherMode},
     database::{Database, QueueItem},
     *,
 };
 use anyhow::Result;
 use notify::{
-    Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+    Config, Event, EventHandler, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode,
+    Watcher,
     event::{MetadataKind, ModifyKind, RenameMode},
 };
-use std::{path::Path, sync::Arc};
+use std::{path::Path, sync::Arc, time::Duration};
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 
 
 #[derive(Debug)]
 pub struct FileWatcher {
-    config: Arc<AppConfig>,
+    config: SharedConfig,
     database: Arc<Database>,
 }
 
 
 impl FileWatcher {
-    pub fn new(config: Arc<AppConfig>, database: Arc<Database>) -> Self {
+    pub fn new(config: SharedConfig, database: Arc<Database>) -> Self {
         FileWatcher {
             config,
             database,
@@ -28,39 +30,137 @@ impl FileWatcher {
     }
 
 
-    pub async fn start(self: Arc<Self>) -> Result<()> {
+    pub async fn start(self: Arc<Self>, shutdown: CancellationToken) -> Result<()> {
         info!("Launching Small Filesystem Handler");
-        let config = self
-            .config
+        let mut config_updates = self.config.subscribe();
+        loop {
+            let targets = self.watch_targets();
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            let _watchers = targets
+                .iter()
+                .map(|(watch_path, mode)| {
+                    info!("Watching path: {watch_path}");
+                    self.watch(watch_path, *mode, tx.clone())
+                })
+                .collect::<Result<Vec<_>>>()?;
+            drop(tx);
+
+            // Keep watchers alive and process events until the watch paths are reconfigured
+            loop {
+                tokio::select! {
+                    event = rx.recv() => match event {
+                        Some(event) => {
+                            if let Err(e) = self.handle_event(event).await {
+                                error!("Error handling file event: {e:?}");
+                            }
+                        }
+                        None => return Ok(()),
+                    },
+                    _ = shutdown.cancelled() => {
+                        info!("File watcher stopped");
+                        return Ok(());
+                    }
+                    changed = config_updates.changed() => {
+                        changed?;
+                        if self.watch_targets() != targets {
+                            info!("Watch path configuration changed. Re-registering the watcher");
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+
+    /// Watch paths with their watcher modes: of the scheduled config and the bound ones
+    fn watch_targets(&self) -> Vec<(String, WatcherMode)> {
+        let app_config = self.config.current();
+        let scheduled = app_config
             .select_config()
             .expect("One of configs should always be selected!");
-        info!("Watching path: {}", config.watch_path);
+        let mut targets = vec![(scheduled.watch_path, scheduled.watcher)];
+        for config in app_config.configs.iter().filter(|cfg| cfg.bind_watch_path) {
+            if !targets
+                .iter()
+                .any(|(watch_path, _)| watch_path == &config.watch_path)
+            {
+                targets.push((config.watch_path.clone(), config.watcher));
+            }
+        }
+        targets
+    }
+
 
-        let (tx, mut rx) = mpsc::unbounded_channel();
-        let mut watcher = RecommendedWatcher::new(
-            move |res: Result<Event, notify::Error>| {
-                if let Ok(event) = res {
-                    let _ = tx.send(event);
+    /// Registers the watch_path with the requested backend.
+    /// Falls back to polling when the native watch registration fails.
+    fn watch(
+        &self,
+        watch_path: &str,
+        mode: WatcherMode,
+        tx: mpsc::UnboundedSender<Event>,
+    ) -> Result<Box<dyn Watcher + Send>> {
+        let path = Path::new(watch_path);
+        if mode == WatcherMode::Native {
+            match RecommendedWatcher::new(Self::event_handler(tx.clone()), Config::default())
+                .and_then(|mut watcher| {
+                    watcher
+                        .watch(path, RecursiveMode::NonRecursive)
+                        .map(|_| watcher)
+                }) {
+                Ok(watcher) => {
+                    info!("Filesystem non-recursive events watcher initialized");
+                    return Ok(Box::new(watcher));
+                }
+                Err(e) => {
+                    warn!(
+                        "Native watcher registration failed for: {watch_path}: {e:?}. Falling back to polling."
+                    );
                 }
-            },
-            Config::default(),
+            }
+        }
+
+        let interval = self.config.current().fs_check_interval;
+        let mut watcher = PollWatcher::new(
+            Self::event_handler(tx),
+            Config::default().with_poll_interval(Duration::from_millis(interval)),
         )?;
+        watcher.watch(path, RecursiveMode::NonRecursive)?;
+        info!("Filesystem non-recursive polling watcher initialized (interval: {interval}ms)");
+        Ok(Box::new(watcher))
+    }
 
-        watcher.watch(Path::new(&config.watch_path), RecursiveMode::NonRecursive)?;
-        info!("Filesystem non-recursive events watcher initialized");
 
-        // Keep watcher alive and process events
-        while let Some(event) = rx.recv().await {
-            if let Err(e) = self.handle_event(event).await {
-                error!("Error handling file event: {e:?}");
+    fn event_handler(tx: mpsc::UnboundedSender<Event>) -> impl EventHandler {
+        move |res: Result<Event, notify::Error>| {
+            if let Ok(event) = res {
+                let _ = tx.send(event);
             }
         }
+    }
 
-        Ok(())
+
+    /// Handles the event on the blocking threads: the database calls and the removals
+    /// of the remote files block
+    async fn handle_event(self: &Arc<Self>, event: Event) -> Result<()> {
+        let watcher = self.clone();
+        tokio::task::spawn_blocking(move || watcher.process(event)).await?
     }
 
 
-    async fn handle_event(&self, event: Event) -> Result<()> {
+    fn process(&self, event: Event) -> Result<()> {
+        if let EventKind::Modify(ModifyKind::Name(RenameMode::Both)) = event.kind
+            && let [from, to] = event.paths.as_slice()
+        {
+            return self.process_rename(&from.to_string_lossy(), &to.to_string_lossy());
+        }
+        if let EventKind::Remove(_) = event.kind {
+            for path in &event.paths {
+                self.process_removal(&path.to_string_lossy())?;
+            }
+            return Ok(());
+        }
+
         for path in event.paths {
             let path_str = path.to_string_lossy().to_string();
             debug!("Handling event: {:?} for path {path_str}", event.kind);
@@ -71,6 +171,11 @@ impl FileWatcher {
                 );
                 continue;
             }
+            // NOTE: the polling watcher reports the watched directory itself too
+            if !path.is_file() {
+                debug!("Not a regular file: {path_str}. Skipping");
+                continue;
+            }
             if TEMP_PATTERN.is_match(&path_str) {
                 debug!("{path_str} matches temp file name! Skipping");
                 continue;
@@ -90,22 +195,78 @@ impl FileWatcher {
 
     fn process_event(&self, file_path: &str) -> Result<()> {
         debug!("Processing event for path: {file_path}");
-        let config = self
-            .config
-            .select_config()
-            .expect("One of configs should always be selected!");
-        let uuid_from_file =
-            uuid::Uuid::new_v3(&uuid::Uuid::NAMESPACE_OID, file_path.as_bytes()).to_string();
-        let remote_dest_file = format!("{}/{uuid_from_file}", config.remote_path);
-
-        // Add to queue
-        let queue_item = QueueItem {
-            local_file: file_path.to_string(),
-            remote_file: remote_dest_file,
-            uuid: uuid_from_file,
-        };
+        let queue_item = self.queue_item(file_path);
         self.database.add_to_queue(&queue_item)?;
         debug!("Added file to queue: {file_path}");
         Ok(())
     }
+
+
+    /// Moves pending uploads of the renamed file to its new path,
+    /// so the uuid (derived from the path) follows the final file name.
+    fn process_rename(&self, from: &str, to: &str) -> Result<()> {
+        debug!("Processing rename: {from} -> {to}");
+        if !Path::new(to).exists() || TEMP_PATTERN.is_match(to) {
+            let dropped = self.database.remove_from_queue_by_file(from)?;
+            debug!("Renamed to a missing or temporary file: {to}. Dropped {dropped} pending");
+            return Ok(());
+        }
+
+        let queue_item = self.queue_item(to);
+        if self.database.move_in_queue(from, &queue_item)? {
+            debug!("Moved pending upload: {from} -> {to}");
+        } else {
+            debug!("Added renamed file to queue: {to}");
+        }
+        Ok(())
+    }
+
+
+    fn process_removal(&self, file_path: &str) -> Result<()> {
+        if Path::new(file_path).exists() {
+            debug!("File: {file_path} was recreated after removal. Skipping");
+            return Ok(());
+        }
+        let app_config = self.config.current();
+        let config = app_config
+            .config_for_path(file_path)
+            .expect("One of configs should always be selected!");
+
+        match config.on_delete {
+            DeletePolicy::Ignore => {
+                debug!("File removed: {file_path}. Ignoring (on_delete = \"ignore\")");
+            }
+            DeletePolicy::Drop | DeletePolicy::Remote => {
+                let dropped = self.database.remove_from_queue_by_file(file_path)?;
+                debug!("File removed: {file_path}. Dropped {dropped} pending uploads");
+            }
+        }
+
+        if config.on_delete == DeletePolicy::Remote
+            && let Some(history) = self.database.find_history_by_file(file_path)?
+            && let Some(remote_name) = history.content.rsplit('/').next()
+        {
+            // NOTE: the file is removed from the host it was uploaded to
+            let uploaded_with = app_config
+                .config_for(history.config.as_deref())
+                .unwrap_or(config);
+            let remote_file = format!("{}/{remote_name}", uploaded_with.remote_path);
+            sftp::remove_remote_file(
+                &uploaded_with,
+                app_config.ssh_connection_timeout,
+                &remote_file,
+            )?;
+        }
+        Ok(())
+    }
+
+
+    fn queue_item(&self, file_path: &str) -> QueueItem {
+        let config = self
+            .config
+            .current()
+            .config_for_path(file_path)
+            .expect("One of configs should always be selected!");
+        QueueItem::new(file_path, &config)
+    }
 }
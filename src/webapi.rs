@@ -1,81 +1,442 @@
-use crate::{config::AppConfig, database::Database, *};
-use anyhow::Result;
+use crate::{
+    config::{AppConfig, SharedConfig},
+    database::{self, Database, HistoryFilter, PauseState},
+    supervisor::Supervisor,
+    *,
+};
+use anyhow::{Context, Result};
 use chrono::{DateTime, Local, Utc};
-use std::{convert::Infallible, sync::Arc};
-use warp::Filter;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, convert::Infallible, path::Path, sync::Arc};
+use tokio_util::bytes::Bytes;
+use warp::{Filter, http::StatusCode};
+
+
+/// Largest body of the POST /upload request
+const UPLOAD_REQUEST_LIMIT: u64 = 64 * 1024;
+
+
+/// Body of the POST /upload request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadRequest {
+    pub files: Vec<String>,
+    pub config: Option<String>, // name of the destination config, the scheduled one if None
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct UploadResponse {
+    pub links: Vec<String>,
+    pub error: Option<String>,
+}
 
 
 #[derive(Debug)]
 pub struct WebApi {
-    config: Arc<AppConfig>,
+    config: SharedConfig,
     database: Arc<Database>,
+    supervisor: Supervisor,
 }
 
 
 impl WebApi {
-    pub fn new(config: Arc<AppConfig>, database: Arc<Database>) -> Self {
+    pub fn new(config: SharedConfig, database: Arc<Database>, supervisor: Supervisor) -> Self {
         WebApi {
             config,
             database,
+            supervisor,
         }
     }
 
 
     pub async fn start(self: Arc<Self>) -> Result<()> {
-        let port = self.config.webapi_port;
+        let port = self.config.current().webapi_port;
         info!("Launching Small WebApi on http://127.0.0.1:{port}");
+        warp::serve(self.routes()).run(([127, 0, 0, 1], port)).await;
+        Ok(())
+    }
+
+
+    /// All the routes of the dashboard and the API
+    pub fn routes(
+        self: Arc<Self>,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        let upload_api = self.clone();
+        let upload = warp::post()
+            .and(warp::path!("upload"))
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::header::optional::<String>("origin"))
+            .and(warp::body::content_length_limit(UPLOAD_REQUEST_LIMIT))
+            .and(warp::body::bytes())
+            .and_then(
+                move |content_type: Option<String>, origin: Option<String>, body: Bytes| {
+                    upload_api.clone().blocking(move |api| {
+                        api.handle_upload(content_type.as_deref(), origin.as_deref(), &body)
+                    })
+                },
+            );
+
+        let status_api = self.clone();
+        let status = warp::get()
+            .and(warp::path!("status"))
+            .and_then(move || status_api.clone().blocking(|api| api.handle_status()));
+
+        let history_api = self.clone();
+        let history = warp::get()
+            .and(warp::path!("history.json"))
+            .and(warp::query::<HashMap<String, String>>())
+            .and_then(move |params: HashMap<String, String>| {
+                history_api
+                    .clone()
+                    .blocking(move |api| api.handle_history_json(&params))
+            });
+
+        let annotate_api = self.clone();
+        let annotate = warp::post()
+            .and(warp::path!("history" / String))
+            .and(warp::header::optional::<String>("origin"))
+            .and(warp::body::form::<HashMap<String, String>>())
+            .and_then(
+                move |uuid: String, origin: Option<String>, form: HashMap<String, String>| {
+                    annotate_api.clone().blocking(move |api| {
+                        api.handle_annotate(&uuid, origin.as_deref(), &form)
+                    })
+                },
+            );
+
+        let pause_api = self.clone();
+        let pause = warp::post()
+            .and(warp::path!("pause"))
+            .and(warp::header::optional::<String>("origin"))
+            .and(warp::body::form::<HashMap<String, String>>())
+            .and_then(
+                move |origin: Option<String>, form: HashMap<String, String>| {
+                    pause_api.clone().blocking(move |api| {
+                        let until = form.get("until").filter(|until| !until.trim().is_empty());
+                        let pause = api
+                            .check_origin(origin.as_deref())
+                            .and_then(|()| PauseState::until(until.map(String::as_str)));
+                        api.handle_pause(pause)
+                    })
+                },
+            );
+        let resume_api = self.clone();
+        let resume = warp::post()
+            .and(warp::path!("resume"))
+            .and(warp::header::optional::<String>("origin"))
+            .and_then(move |origin: Option<String>| {
+                resume_api.clone().blocking(move |api| {
+                    let pause = api
+                        .check_origin(origin.as_deref())
+                        .map(|()| PauseState::default());
+                    api.handle_pause(pause)
+                })
+            });
 
         let web_api = self.clone();
         let routes = warp::path::end()
-            .and(warp::query::<std::collections::HashMap<String, String>>())
+            .and(warp::query::<HashMap<String, String>>())
             .and_then(move |params| {
-                let api = web_api.clone();
-                async move { api.handle_request(params).await }
+                web_api
+                    .clone()
+                    .blocking(move |api| api.handle_request(params))
             })
             .or(warp::path::param().and_then(move |count: usize| {
-                let api = self.clone();
-                async move { api.handle_count_request(count).await }
+                self.clone()
+                    .blocking(move |api| api.handle_count_request(count))
             }));
 
-        warp::serve(routes).run(([127, 0, 0, 1], port)).await;
-        Ok(())
+        upload
+            .or(status)
+            .or(history)
+            .or(annotate)
+            .or(pause)
+            .or(resume)
+            .or(routes)
     }
 
 
-    async fn handle_request(
-        &self,
-        _params: std::collections::HashMap<String, String>,
-    ) -> Result<impl warp::Reply + use<>, Infallible> {
-        let limit = self.config.amount_history_load;
-        debug!("Loading history of {limit} elements (default)");
+    /// Runs the handler on the blocking threads, so its database calls never stall the server
+    async fn blocking<R, F>(self: Arc<Self>, handler: F) -> Result<R, Infallible>
+    where
+        R: Send + 'static,
+        F: FnOnce(&WebApi) -> R + Send + 'static,
+    {
+        let reply = tokio::task::spawn_blocking(move || handler(&self)).await;
+        Ok(reply.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic())))
+    }
+
+
+    /// Filter of the search, since, until, type, tag, after and limit parameters
+    fn history_filter(&self, params: &HashMap<String, String>) -> Result<HistoryFilter> {
+        let param = |name: &str| {
+            params
+                .get(name)
+                .map(|value| value.trim())
+                .filter(|value| !value.is_empty())
+        };
+        let limit = param("limit")
+            .and_then(|limit| limit.parse().ok())
+            .unwrap_or(self.config.current().amount_history_load);
+        Ok(HistoryFilter {
+            after: param("after").map(String::from),
+            ..HistoryFilter::new(
+                param("search"),
+                param("since"),
+                param("until"),
+                param("type"),
+                param("tag"),
+                limit,
+            )?
+        })
+    }
+
 
-        match self.database.get_history(Some(limit)) {
-            Ok(history) => Ok(warp::reply::html(self.render_history(&history))),
+    /// The dashboard, optionally searched with the search, since, until, type and limit parameters,
+    /// the older entries are listed after the one of the uuid parameter: after
+    fn handle_request(&self, params: HashMap<String, String>) -> impl warp::Reply + use<> {
+        debug!("Loading history, params: {params:?}");
+        let history = self.history_filter(&params).and_then(|filter| {
+            let history = self.database.search_history(&filter)?;
+            let more = history.len() == filter.limit;
+            Ok((history, more))
+        });
+        match history {
+            Ok((history, more)) => {
+                warp::reply::with_status(
+                    warp::reply::html(self.render_history(&history, &params, more)),
+                    StatusCode::OK,
+                )
+            }
             Err(e) => {
                 error!("Error getting history: {e:?}");
-                Ok(warp::reply::html(format!("Error: {e:?}")))
+                Self::error_page(&e)
+            }
+        }
+    }
+
+
+    /// Queues the files of the JSON request. Web pages the user visits can send requests
+    /// here too, so only the JSON ones without a foreign Origin are taken, and only files
+    /// of the watch paths are uploaded.
+    fn handle_upload(
+        &self,
+        content_type: Option<&str>,
+        origin: Option<&str>,
+        body: &[u8],
+    ) -> impl warp::Reply + use<> {
+        let app_config = self.config.current();
+        let links = Self::upload_request(&app_config, content_type, origin, body).and_then(
+            |request| {
+                info!(
+                    "Upload request of {} files, config: {:?}",
+                    request.files.len(),
+                    request.config
+                );
+                request
+                    .files
+                    .iter()
+                    .map(|file| {
+                        if !app_config.is_watched(Path::new(file)) {
+                            anyhow::bail!("Not in any of the watch paths: {file}");
+                        }
+                        sftp::enqueue_file(
+                            &app_config,
+                            &self.database,
+                            file,
+                            request.config.as_deref(),
+                        )
+                    })
+                    .collect::<Result<Vec<_>>>()
+            },
+        );
+
+        match links {
+            Ok(links) => {
+                warp::reply::with_status(
+                    warp::reply::json(&UploadResponse {
+                        links,
+                        error: None,
+                    }),
+                    StatusCode::OK,
+                )
+            }
+            Err(e) => {
+                error!("Upload request failed: {e:?}");
+                warp::reply::with_status(
+                    warp::reply::json(&UploadResponse {
+                        links: Vec::new(),
+                        error: Some(format!("{e:#}")),
+                    }),
+                    StatusCode::BAD_REQUEST,
+                )
+            }
+        }
+    }
+
+
+    /// The upload request, when it's JSON and comes from no page or the dashboard
+    pub fn upload_request(
+        app_config: &AppConfig,
+        content_type: Option<&str>,
+        origin: Option<&str>,
+        body: &[u8],
+    ) -> Result<UploadRequest> {
+        let is_json = content_type.is_some_and(|content_type| {
+            content_type
+                .split(';')
+                .next()
+                .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+        });
+        if !is_json {
+            anyhow::bail!("Content-Type: application/json is required");
+        }
+        check_origin(origin, app_config.webapi_port)?;
+        serde_json::from_slice(body).context("Invalid upload request")
+    }
+
+
+    /// States of the subsystems, with the queue and the pause
+    fn handle_status(&self) -> impl warp::Reply + use<> {
+        let subsystems = self.supervisor.statuses();
+        let pause = self.database.get_pause().unwrap_or_default();
+        let healthy = subsystems.values().all(|status| !status.gave_up);
+        warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "version": env!("CARGO_PKG_VERSION"),
+                "subsystems": subsystems,
+                "queued": self.database.queue_length().unwrap_or_default(),
+                "paused": pause.is_active(Local::now().timestamp()),
+                "paused_until": pause.until_local(),
+            })),
+            if healthy {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            },
+        )
+    }
+
+
+    /// History entries with their upload metadata, searched like the dashboard
+    fn handle_history_json(
+        &self,
+        params: &HashMap<String, String>,
+    ) -> impl warp::Reply + use<> {
+        let history = self
+            .history_filter(params)
+            .and_then(|filter| self.database.search_history(&filter));
+        match history {
+            Ok(history) => {
+                warp::reply::with_status(warp::reply::json(&history), StatusCode::OK)
+            }
+            Err(e) => {
+                warn!("Error getting history: {e:?}");
+                warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({ "error": format!("{e:#}") })),
+                    StatusCode::BAD_REQUEST,
+                )
             }
         }
     }
 
 
-    async fn handle_count_request(
+    /// Replaces the tags and the note of the entry, goes back to the dashboard
+    fn handle_annotate(
         &self,
-        count: usize,
-    ) -> Result<impl warp::Reply + use<>, Infallible> {
+        uuid: &str,
+        origin: Option<&str>,
+        form: &HashMap<String, String>,
+    ) -> impl warp::Reply + use<> {
+        // NOTE: the tags are separated by commas or whitespace, normalized by set_tags
+        let tags = [form.get("tags").cloned().unwrap_or_default()];
+        let note = form.get("note").map(String::as_str);
+        let annotated = self.check_origin(origin).and_then(|()| {
+            if !self.database.set_tags(uuid, &tags)? {
+                anyhow::bail!("No history entry: {uuid}");
+            }
+            self.database.set_note(uuid, note)
+        });
+        match annotated {
+            Ok(_) => {
+                let location = format!("/#{uuid}");
+                warp::reply::with_status(
+                    warp::reply::with_header(warp::reply(), "Location", location),
+                    StatusCode::SEE_OTHER,
+                )
+            }
+            Err(e) => {
+                warn!("Annotate request failed: {e:?}");
+                warp::reply::with_status(
+                    warp::reply::with_header(warp::reply(), "X-Error", format!("{e:#}")),
+                    StatusCode::BAD_REQUEST,
+                )
+            }
+        }
+    }
+
+
+    /// Refuses the forms posted by other web pages than the dashboard
+    fn check_origin(&self, origin: Option<&str>) -> Result<()> {
+        check_origin(origin, self.config.current().webapi_port)
+    }
+
+
+    /// Stores the pause and goes back to the dashboard
+    fn handle_pause(&self, pause: Result<PauseState>) -> impl warp::Reply + use<> {
+        match pause.and_then(|pause| self.database.set_pause(&pause)) {
+            Ok(()) => {
+                warp::reply::with_status(
+                    warp::reply::with_header(warp::reply(), "Location", "/"),
+                    StatusCode::SEE_OTHER,
+                )
+            }
+            Err(e) => {
+                error!("Pause request failed: {e:?}");
+                warp::reply::with_status(
+                    warp::reply::with_header(warp::reply(), "X-Error", format!("{e:#}")),
+                    StatusCode::BAD_REQUEST,
+                )
+            }
+        }
+    }
+
+
+    fn handle_count_request(&self, count: usize) -> impl warp::Reply + use<> {
         info!("Loading history of {count} elements.");
 
         match self.database.get_history(Some(count)) {
-            Ok(history) => Ok(warp::reply::html(self.render_history(&history))),
+            Ok(history) => {
+                warp::reply::with_status(
+                    warp::reply::html(self.render_history(&history, &HashMap::new(), false)),
+                    StatusCode::OK,
+                )
+            }
             Err(e) => {
                 error!("Error getting history: {e:?}");
-                Ok(warp::reply::html(format!("Error: {e:?}")))
+                Self::error_page(&e)
             }
         }
     }
 
 
-    fn render_history(&self, history: &[database::History]) -> String {
+    /// The error of the request, escaped: it can hold the parameters, e.g. Invalid date: "<script>"
+    fn error_page(
+        error: &anyhow::Error,
+    ) -> warp::reply::WithStatus<warp::reply::Html<String>> {
+        warp::reply::with_status(
+            warp::reply::html(format!("Error: {}", html_escape(&format!("{error:#}")))),
+            StatusCode::BAD_REQUEST,
+        )
+    }
+
+
+    pub fn render_history(
+        &self,
+        history: &[database::History],
+        params: &HashMap<String, String>,
+        more: bool,
+    ) -> String {
         let count = history.len();
         let items: Vec<String> = history
             .iter()
@@ -89,11 +450,24 @@ impl WebApi {
                     .unwrap_or_else(|| entry.timestamp.to_string());
 
                 let links: Vec<&str> = entry.content.split(' ').collect();
-                let links_html = self.extract_links(&timestamp, &links, &entry.file);
+                let caption = match &entry.config {
+                    Some(config) => format!("{timestamp} - {} - {config}", entry.file),
+                    None => format!("{timestamp} - {}", entry.file),
+                };
+                let links_html = self.extract_links(&html_escape(&caption), &links);
+                let details = entry.details();
+                let details_html = if details.is_empty() {
+                    String::new()
+                } else {
+                    format!(r#"<span class="details">{}</span>"#, html_escape(&details))
+                };
 
                 format!(
-                    "<article id=\"{}\" class=\"text-center\">{}</article>",
-                    entry.uuid, links_html
+                    "<article id=\"{}\" class=\"text-center\">{}{}{}</article>",
+                    html_escape(&entry.uuid),
+                    links_html,
+                    details_html,
+                    Self::render_annotations(entry)
                 )
             })
             .collect();
@@ -103,37 +477,133 @@ impl WebApi {
 {}
 <body>
 <pre class="count"><span>small</span> history of: {count}</pre>
+{}
+{}
 <div>
 {}
 </div>
+{}
 <footer>
 <pre class="count">Sync eM ALL - version: {} - © 2015-2025 - Daniel (<a href="https://x.com/dmilith/" target="_blank">@dmilith</a>) Dettlaff</pre>
 </footer>
 </body>
 </html>"#,
             Self::head(),
+            self.render_status(),
+            Self::render_search(params),
             items.join(" "),
+            history
+                .last()
+                .filter(|_| more)
+                .map(|last| Self::render_more(params, &last.uuid))
+                .unwrap_or_default(),
             env!("CARGO_PKG_VERSION")
         )
     }
 
 
-    fn extract_links(&self, timestamp: &str, links: &[&str], file: &str) -> String {
+    /// Amount of the queued files with the pause toggles
+    fn render_status(&self) -> String {
+        let waiting = self.database.queue_length().unwrap_or_default();
+        let pause = self.database.get_pause().unwrap_or_default();
+        if pause.is_active(Local::now().timestamp()) {
+            let until = pause
+                .until_local()
+                .map(|until| format!(" until {until}"))
+                .unwrap_or_default();
+            format!(
+                r#"<form class="status" method="post" action="/resume"><pre class="count">Uploading paused{until} - waiting: {waiting} <button type="submit">Resume</button></pre></form>"#
+            )
+        } else {
+            format!(
+                r#"<form class="status" method="post" action="/pause"><pre class="count">Waiting: {waiting} <input name="until" placeholder="30m, 2h or 18:00" size="16"> <button type="submit">Pause</button></pre></form>"#
+            )
+        }
+    }
+
+
+    /// Tags linking to their listings, the note and the form editing them
+    fn render_annotations(entry: &database::History) -> String {
+        let tags: Vec<String> = entry
+            .tags
+            .iter()
+            .map(|tag| {
+                format!(
+                    r##"<a href="/?tag={}">#{}</a>"##,
+                    url_encode(tag),
+                    html_escape(tag)
+                )
+            })
+            .collect();
+        let note = entry.note.as_deref().map(html_escape).unwrap_or_default();
+        format!(
+            r#"<span class="tags">{} {note}</span><form class="annotate" method="post" action="/history/{}"><input name="tags" placeholder="tags" size="20" value="{}"> <input name="note" placeholder="note" size="32" value="{note}"> <button type="submit">Save</button></form>"#,
+            tags.join(" "),
+            html_escape(&entry.uuid),
+            html_escape(&entry.tags.join(", "))
+        )
+    }
+
+
+    /// Search form keeping the current parameters
+    fn render_search(params: &HashMap<String, String>) -> String {
+        let value = |name: &str| {
+            params
+                .get(name)
+                .map(|value| html_escape(value))
+                .unwrap_or_default()
+        };
+        format!(
+            r#"<form class="search" method="get" action="/"><pre class="count"><input name="search" placeholder="search" size="24" value="{}"> <input name="since" placeholder="since: 7d, 2025-09-01" size="16" value="{}"> <input name="until" placeholder="until: 2025-09-30" size="16" value="{}"> <input name="type" placeholder="type: image, pdf" size="12" value="{}"> <input name="tag" placeholder="tag" size="12" value="{}"> <button type="submit">Search</button></pre></form>"#,
+            value("search"),
+            value("since"),
+            value("until"),
+            value("type"),
+            value("tag")
+        )
+    }
+
+
+    /// Button of the older entries, keeping the current parameters
+    fn render_more(params: &HashMap<String, String>, after: &str) -> String {
+        let mut inputs: Vec<String> = params
+            .iter()
+            .filter(|(name, _)| name.as_str() != "after")
+            .map(|(name, value)| {
+                format!(
+                    r#"<input type="hidden" name="{}" value="{}">"#,
+                    html_escape(name),
+                    html_escape(value)
+                )
+            })
+            .collect();
+        inputs.sort();
+        format!(
+            r#"<form class="more" method="get" action="/"><pre class="count">{}<input type="hidden" name="after" value="{}"><button type="submit">Older</button></pre></form>"#,
+            inputs.join(""),
+            html_escape(after)
+        )
+    }
+
+
+    /// Previews of the links with the caption, escaped already
+    fn extract_links(&self, caption: &str, links: &[&str]) -> String {
         links
             .iter()
-            .filter(|l| !l.is_empty())
+            .filter(|link| is_web_link(link))
             .map(|link| {
+                let link = html_escape(link);
                 if link.ends_with("png")
                     || link.ends_with("jpg")
                     || link.ends_with("jpeg")
                     || link.ends_with("gif")
                 {
                     format!(
-                        r#"<a href="{link}"><img src="{link}"></img><span class="caption">{timestamp} - {file}</span></a>"#
+                        r#"<a href="{link}"><img src="{link}"></img><span class="caption">{caption}</span></a>"#
                     )
                 } else {
                     format!(
-                        r#"<a href="{link}"><img src="{IMG_NO_MEDIA}"><span class="caption">{timestamp} - {file}</span></div></a>"#
+                        r#"<a href="{link}"><img src="{IMG_NO_MEDIA}"><span class="caption">{caption}</span></div></a>"#
                     )
                 }
             })
@@ -151,6 +621,9 @@ impl WebApi {
     article.item { vertical-align: top; display: block; text-align: center; }
     img { background-color: grey; padding: 0.5em; margin-top: 3em; margin-left: 2em; margin-right: 2em; }
     .caption { display: block; }
+    .details { display: block; font-size: 0.8em; color: #555; }
+    .tags { display: block; }
+    form.annotate { display: block; margin-top: 0.3em; }
     .count { display: block; margin: 0.5em; font-weight: bold; text-align: center; background: #CFCFCF }
     pre.count { margin: 2em; }
     pre.count span { font-size: 1.6em; }
@@ -160,3 +633,22 @@ impl WebApi {
 </head>"#
     }
 }
+
+
+/// Requests without an Origin (not from a browser) or from the dashboard itself
+fn check_origin(origin: Option<&str>, port: u16) -> Result<()> {
+    match origin {
+        Some(origin) if !is_local_origin(origin, port) => {
+            anyhow::bail!("Requests from: {origin} aren't allowed")
+        }
+        _ => Ok(()),
+    }
+}
+
+
+/// True for the Origin of the dashboard itself
+fn is_local_origin(origin: &str, port: u16) -> bool {
+    ["127.0.0.1", "localhost", "[::1]"]
+        .iter()
+        .any(|host| origin.eq_ignore_ascii_case(&format!("http://{host}:{port}")))
+}